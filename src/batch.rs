@@ -0,0 +1,153 @@
+// Batch Run Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// 端末を使わずにプログラムを送って RUN し、出力を集める (msxterm run)
+// CI でシミュレータと組み合わせて BASIC プログラムの出力を確かめる
+//
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+use regex::Regex;
+use rustyline::ExternalPrinter;
+use crate::error::{self, MsxError};
+use crate::{transfer, Command, Link, Msxterm, C_CR, U_BREAK};
+
+/// 実行の終わり方
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// 終わりの行を受信した
+    Finished,
+    /// "error in" を含む行があった
+    Error,
+    /// 終わりの行が届かなかった
+    Timeout,
+}
+
+impl Outcome {
+    // プロセスの終了コード
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Finished => 0,
+            Outcome::Error => 1,
+            Outcome::Timeout => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RunResult {
+    /// RUN のエコーから終わりの行の手前までに受信した行
+    pub output: Vec<String>,
+    pub outcome: Outcome,
+}
+
+// MSX の実行時エラー (Syntax error in 10 など)
+fn is_run_error(line: &str) -> bool {
+    line.to_lowercase().contains("error in")
+}
+
+// エラーの行があれば Error、なければ outcome
+fn finish(output: Vec<String>, outcome: Outcome) -> RunResult {
+    let outcome = if output.iter().any(|l| is_run_error(l)) { Outcome::Error } else { outcome };
+    RunResult { output, outcome }
+}
+
+// 受信スレッドの表示 (切断など) は標準エラー出力に書く
+// 標準出力はプログラムの出力だけにする
+#[derive(Default)]
+pub struct StderrPrinter {
+    last: String,
+}
+
+impl ExternalPrinter for StderrPrinter {
+    fn print(&mut self, msg: String) -> rustyline::Result<()> {
+        let text = crate::plain_text(&msg, &mut self.last);
+        if !text.trim_end().is_empty() {
+            eprintln!("{}", text.trim_end());
+        }
+        Ok(())
+    }
+}
+
+//
+// プログラムを NEW してから送り (#load と同じく一行ずつ応答を待つ)、RUN の出力を集める
+// until に一致する行を受信するか timeout が過ぎたら終わる
+//
+pub fn run_program(msxterm: &Msxterm, link: &Link, lines: &[String], until: &Regex, timeout: Duration)
+    -> error::Result<RunResult> {
+    transfer::reload_to(msxterm, link, lines)?;
+    // watch の受付を待つ間に Quiet も処理される
+    link.command(Command::Quiet(true))?;
+    let result = link.watch().and_then(|rx| collect(msxterm, link, &rx, until, timeout));
+    link.unwatch();
+    let _ = link.command(Command::Quiet(false));
+    result
+}
+
+fn collect(msxterm: &Msxterm, link: &Link, rx: &Receiver<String>, until: &Regex, timeout: Duration)
+    -> error::Result<RunResult> {
+    link.send(&msxterm.encode(&format!("RUN{}", C_CR))?)?;
+    let deadline = Instant::now() + timeout;
+    let mut output: Vec<String> = Vec::new();
+    let mut echoed = false;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let line = match transfer::recv_line(rx, remaining, "RUN") {
+            Ok(line) => line.trim_end().to_string(),
+            Err(MsxError::Timeout(_)) => {
+                // 止まらないプログラムは中断しておく
+                link.send(&[U_BREAK])?;
+                return Ok(finish(output, Outcome::Timeout));
+            },
+            Err(e) => return Err(e),
+        };
+        // RUN のエコーより前の行は読み捨てる
+        if !echoed {
+            echoed = transfer::is_echo(&line, "RUN");
+            continue;
+        }
+        if until.is_match(&line) {
+            return Ok(finish(output, Outcome::Finished));
+        }
+        output.push(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::tests::sim_link;
+
+    fn program(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn test_run_program() {
+        let link = sim_link();
+        let mt = Msxterm::new();
+        let ok = Regex::new("^Ok$").unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let lines = program(&["10 PRINT \"HELLO\"", "20 PRINT \"WORLD\"", "30 END"]);
+        let result = run_program(&mt, &link, &lines, &ok, timeout).unwrap();
+        assert_eq!(result.output, ["HELLO", "WORLD"]);
+        assert_eq!(result.outcome, Outcome::Finished);
+
+        let lines = program(&["10 PRINT \"A\"", "20 ERROR 2"]);
+        let result = run_program(&mt, &link, &lines, &ok, timeout).unwrap();
+        assert_eq!(result.output, ["A", "Syntax error in 20"]);
+        assert_eq!(result.outcome, Outcome::Error);
+
+        // 終わらないプログラムは中断される
+        let lines = program(&["10 GOTO 10"]);
+        let result = run_program(&mt, &link, &lines, &ok, Duration::from_millis(500)).unwrap();
+        assert_eq!(result.outcome, Outcome::Timeout);
+        let done = Regex::new("^DONE$").unwrap();
+        let lines = program(&["10 PRINT \"DONE\""]);
+        let result = run_program(&mt, &link, &lines, &done, timeout).unwrap();
+        assert_eq!(result.output, Vec::<String>::new());
+        assert_eq!(result.outcome, Outcome::Finished);
+    }
+}
@@ -0,0 +1,37 @@
+// MSX0 Simulator
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+#[path = "../sim.rs"]
+#[allow(dead_code)]
+mod sim;
+
+use clap::Parser;
+
+//
+// コマンドラインオプションの設定
+//
+#[derive(Parser, Debug)]
+#[command(author, version, about = "MSX0 simulator server for msxterm", long_about = None)]
+struct Args {
+    /// Listen address
+    #[arg(default_value = "127.0.0.1:2223")]
+    addr: String,
+}
+
+fn main() {
+    let args = Args::parse();
+    match sim::Simulator::bind(&args.addr) {
+        Ok(simulator) => {
+            if let Ok(addr) = simulator.local_addr() {
+                println!("MSX0 simulator listening on {}", addr);
+            }
+            simulator.run();
+        },
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
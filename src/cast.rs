@@ -0,0 +1,204 @@
+// Session Record / Replay Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// 端末への出力を asciicast v2 (asciinema の形式) で記録し、再生する
+// sessionlog の .raw ファイル (RX) も msxcode でデコードして再生できる
+//
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::error::{self, MsxError};
+use crate::msxcode;
+use crate::screen::SCREEN_HEIGHT;
+
+/// 出力イベント (経過秒, 出力した文字列)
+pub type Event = (f64, String);
+
+// 改行だけの LF は端末では行頭に戻らないので CR を補う
+fn terminal_newlines(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut prev = '\0';
+    for c in text.chars() {
+        if c == '\n' && prev != '\r' {
+            out.push('\r');
+        }
+        out.push(c);
+        prev = c;
+    }
+    out
+}
+
+pub struct Recorder {
+    path: PathBuf,
+    file: BufWriter<File>,
+    start: Instant,
+    // 直前に書いた文字 (CR LF が分かれて届いた場合用)
+    last: char,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, width: usize) -> error::Result<Recorder> {
+        let file_err = |e| MsxError::File(path.display().to_string(), e);
+        let mut file = BufWriter::new(File::create(path).map_err(file_err)?);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let term = std::env::var("TERM").unwrap_or("xterm-256color".to_string());
+        let header = serde_json::json!({
+            "version": 2,
+            "width": width,
+            "height": SCREEN_HEIGHT,
+            "timestamp": timestamp,
+            "title": "msxterm",
+            "env": { "TERM": term },
+        });
+        writeln!(file, "{}", header).map_err(file_err)?;
+        Ok(Recorder { path: path.to_path_buf(), file, start: Instant::now(), last: '\0' })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // 端末に出力した文字列
+    // 書き込みに失敗しても通信は続けたいので、エラーは無視する
+    pub fn output(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let mut data = terminal_newlines(text);
+        if self.last == '\r' && data.starts_with("\r\n") {
+            data.remove(0);
+        }
+        self.last = text.chars().last().unwrap_or('\0');
+        let event = (self.start.elapsed().as_secs_f64(), "o", data);
+        if let Ok(line) = serde_json::to_string(&event) {
+            let _ = writeln!(self.file, "{}", line);
+        }
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.file.flush();
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+// asciicast v2 の出力イベントを読む
+pub fn parse_cast(text: &str) -> error::Result<Vec<Event>> {
+    let bad = |n: usize| MsxError::Replay(format!("line {}: not an asciicast v2 event", n + 1));
+    let mut lines = text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty());
+    let header: serde_json::Value = lines.next()
+        .and_then(|(_, l)| serde_json::from_str(l).ok())
+        .ok_or(MsxError::Replay("not an asciicast file".to_string()))?;
+    if header["version"] != 2 {
+        return Err(MsxError::Replay("only asciicast version 2 is supported".to_string()));
+    }
+    let mut events = Vec::new();
+    for (n, line) in lines {
+        let (time, kind, data): (f64, String, String) = serde_json::from_str(line).map_err(|_| bad(n))?;
+        // 入力イベント ("i") などは再生しない
+        if kind == "o" {
+            events.push((time, data));
+        }
+    }
+    Ok(events)
+}
+
+// sessionlog の .raw ファイルから受信データを取り出してデコードする
+pub fn decode_raw_log(text: &str, kanji_mode: bool) -> error::Result<Vec<Event>> {
+    let mut decoder = msxcode::MsxDecoder::new(kanji_mode);
+    let mut events = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        if line.starts_with('#') || line.trim().is_empty() {
+            continue;
+        }
+        let bad = || MsxError::Replay(format!("line {}: not a raw log line", n + 1));
+        let mut fields = line.split_whitespace();
+        let time = fields.next().and_then(|t| t.parse::<f64>().ok()).ok_or_else(bad)?;
+        match fields.next() {
+            Some("RX") => {},
+            Some("TX") => continue,
+            _ => return Err(bad()),
+        }
+        let bytes = fields.map(|h| u8::from_str_radix(h, 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| bad())?;
+        events.push((time, terminal_newlines(&decoder.decode(&bytes))));
+    }
+    Ok(events)
+}
+
+// 時間を合わせて標準出力に流す (speed 倍速、待ち時間は idle_limit 秒まで)
+pub fn play(events: &[Event], speed: f64, idle_limit: Option<f64>) -> error::Result<()> {
+    let mut stdout = std::io::stdout();
+    let mut prev = 0.0;
+    for (time, data) in events {
+        let mut wait = (time - prev).max(0.0) / speed;
+        if let Some(limit) = idle_limit {
+            wait = wait.min(limit);
+        }
+        let wait = Duration::try_from_secs_f64(wait)
+            .map_err(|_| MsxError::Replay(format!("bad wait time at {} sec", time)))?;
+        std::thread::sleep(wait);
+        prev = *time;
+        stdout.write_all(data.as_bytes()).map_err(MsxError::Terminal)?;
+        stdout.flush().map_err(MsxError::Terminal)?;
+    }
+    Ok(())
+}
+
+// ファイルの形式を見分けて再生する
+pub fn replay(path: &Path, speed: f64, idle_limit: Option<f64>, kanji_mode: bool) -> error::Result<()> {
+    let text = std::fs::read_to_string(path).map_err(|e| MsxError::File(path.display().to_string(), e))?;
+    let events = if text.trim_start().starts_with('{') {
+        parse_cast(&text)?
+    } else {
+        decode_raw_log(&text, kanji_mode)?
+    };
+    play(&events, speed, idle_limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let path = std::env::temp_dir().join(format!("msxterm_cast_{}.cast", std::process::id()));
+        {
+            let mut rec = Recorder::create(&path, 40).unwrap();
+            rec.output("PRINT \"A\"\r");
+            rec.output("\nA\r\nOk\n");
+        }
+        let text = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let header: serde_json::Value = serde_json::from_str(text.lines().next().unwrap()).unwrap();
+        assert_eq!(header["width"], 40);
+        let events = parse_cast(&text).unwrap();
+        let data: Vec<&str> = events.iter().map(|(_, d)| d.as_str()).collect();
+        assert_eq!(data, ["PRINT \"A\"\r", "\nA\r\nOk\r\n"]);
+        assert!(parse_cast("{\"version\":1}").is_err());
+    }
+
+    #[test]
+    fn test_play() {
+        let events = [(0.0, String::new()), (1.0, String::new())];
+        assert!(play(&events, 0.0, None).is_err());
+        assert!(play(&events, 1.0, Some(-1.0)).is_err());
+        play(&events, 100.0, Some(0.0)).unwrap();
+    }
+
+    #[test]
+    fn test_decode_raw_log() {
+        let log = "# msxterm log started\n0.100 TX 41 0D\n0.120 RX 41 0D 0A\n0.500 RX 4F 6B 0A\n";
+        let events = decode_raw_log(log, false).unwrap();
+        assert_eq!(events, [(0.12, "A\r\n".to_string()), (0.5, "Ok\r\n".to_string())]);
+        assert!(decode_raw_log("0.1 RX ZZ", false).is_err());
+    }
+}
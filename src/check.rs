@@ -0,0 +1,204 @@
+// MSX-BASIC Static Check Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// プログラムバッファを RUN する前に調べる
+//
+use std::collections::{BTreeMap, BTreeSet};
+use crate::lexer::{self, Kind, Lexeme};
+use crate::{msxcode, tokenize};
+
+// 中間言語にした一行の上限
+const MAX_LINE_BYTES: usize = 255;
+
+// 範囲を指定する命令 (行がなくてもエラーにならない)
+const RANGE_KEYWORDS: [&str; 3] = ["LIST", "LLIST", "DELETE"];
+
+/// 見つかった問題 (行番号, 内容)
+pub type Problem = (u16, String);
+
+// 変数名を (比較用の名前, 表示用の名前) にする
+// MSX は先頭2文字と型で区別する
+fn variable_key(name: &str) -> (String, String) {
+    let upper = name.to_ascii_uppercase();
+    let (body, suffix) = match upper.chars().last() {
+        Some(c) if "$%!#".contains(c) => (&upper[..upper.len() - 1], c.to_string()),
+        _ => (&upper[..], String::new()),
+    };
+    (format!("{}{}", &body[..body.len().min(2)], suffix), upper)
+}
+
+// 空白を除いた次の字句
+fn next_solid<'a>(lexemes: &'a [Lexeme<'a>], i: usize) -> Option<&'a Lexeme<'a>> {
+    lexemes[i..].iter().find(|l| l.kind != Kind::Space)
+}
+
+pub fn check(program: &BTreeMap<u16, String>) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    // FOR の変数 (行番号, 変数名)
+    let mut for_stack: Vec<(u16, String)> = Vec::new();
+    // 先頭2文字ごとの変数名
+    let mut variables: BTreeMap<String, (u16, BTreeSet<String>)> = BTreeMap::new();
+
+    for (&num, line) in program {
+        let code = msxcode::utf8_msx_jp_code(line);
+        match tokenize::tokenize_line(&code) {
+            Ok(body) if body.len() > MAX_LINE_BYTES => {
+                problems.push((num, format!("Line too long ({} bytes tokenized)", body.len())));
+            },
+            Ok(_) => {},
+            Err(e) => problems.push((num, e.to_string())),
+        }
+
+        let lexemes = lexer::lex(line);
+        let mut depth = 0_i32;
+        let mut last_keyword = String::new();
+        // 文の先頭か
+        let mut statement_start = true;
+        // CALL の後ろは拡張命令なので調べない
+        let mut in_call = false;
+        for (i, lexeme) in lexemes.iter().enumerate() {
+            match lexeme.kind {
+                Kind::Space => continue,
+                Kind::Str if lexeme.text.len() < 2 || !lexeme.text.ends_with('"') => {
+                    problems.push((num, "Unterminated string".to_string()));
+                },
+                Kind::LineRef => {
+                    let target = lexeme.text.parse::<u16>().ok();
+                    let resume0 = last_keyword == "RESUME" && target == Some(0);
+                    if !RANGE_KEYWORDS.contains(&last_keyword.as_str()) && !resume0 {
+                        if let Some(t) = target.filter(|t| !program.contains_key(t)) {
+                            problems.push((num, format!("Undefined line {} after {}", t, last_keyword)));
+                        }
+                    }
+                },
+                Kind::Keyword | Kind::Function => {
+                    let keyword = lexeme.keyword().unwrap_or_default();
+                    if keyword.ends_with('(') {
+                        depth += 1;
+                    }
+                    match keyword.as_str() {
+                        "CALL" => in_call = true,
+                        "FOR" => {
+                            let var = next_solid(&lexemes, i + 1)
+                                .filter(|l| l.kind == Kind::Name)
+                                .map(|l| l.text.to_ascii_uppercase())
+                                .unwrap_or_default();
+                            for_stack.push((num, var));
+                        },
+                        "NEXT" => {
+                            // NEXT I,J は二つ閉じる
+                            let mut vars = Vec::new();
+                            for l in lexemes[i + 1..].iter().filter(|l| l.kind != Kind::Space) {
+                                match l.kind {
+                                    Kind::Name => vars.push(l.text.to_ascii_uppercase()),
+                                    Kind::Punct if l.text == "," => {},
+                                    _ => break,
+                                }
+                            }
+                            if vars.is_empty() && for_stack.pop().is_none() {
+                                problems.push((num, "NEXT without FOR".to_string()));
+                            }
+                            for var in vars {
+                                match for_stack.iter().rposition(|(_, v)| *v == var) {
+                                    Some(pos) => for_stack.truncate(pos),
+                                    None => problems.push((num, format!("NEXT {} without FOR", var))),
+                                }
+                            }
+                        },
+                        _ => {},
+                    }
+                    last_keyword = keyword;
+                    statement_start = matches!(last_keyword.as_str(), "THEN" | "ELSE");
+                    continue;
+                },
+                Kind::Name if !in_call => {
+                    // 文の先頭の変数名は代入 (名前 = / 名前( ) のはず
+                    if statement_start {
+                        let assign = next_solid(&lexemes, i + 1)
+                            .is_some_and(|l| l.text == "=" || l.text == "(");
+                        if !assign {
+                            problems.push((num, format!("Unknown keyword {}", lexeme.text.to_ascii_uppercase())));
+                        }
+                    }
+                    let (key, name) = variable_key(lexeme.text);
+                    variables.entry(key).or_insert_with(|| (num, BTreeSet::new())).1.insert(name);
+                },
+                Kind::Punct => {
+                    match lexeme.text {
+                        "(" => depth += 1,
+                        ")" => {
+                            depth -= 1;
+                            if depth < 0 {
+                                problems.push((num, "Unbalanced ')'".to_string()));
+                                depth = 0;
+                            }
+                        },
+                        ":" => {
+                            in_call = false;
+                            statement_start = true;
+                            continue;
+                        },
+                        // _ は CALL の省略形
+                        "_" if statement_start => in_call = true,
+                        _ => {},
+                    }
+                },
+                _ => {},
+            }
+            statement_start = false;
+        }
+        if depth > 0 {
+            problems.push((num, "Unbalanced '('".to_string()));
+        }
+    }
+    for (num, var) in for_stack {
+        let name = if var.is_empty() { String::new() } else { format!(" {}", var) };
+        problems.push((num, format!("FOR{} without NEXT", name)));
+    }
+    for (key, (num, names)) in variables {
+        if names.len() > 1 {
+            let names: Vec<String> = names.into_iter().collect();
+            problems.push((num, format!("Variables {} are the same variable {}", names.join(", "), key)));
+        }
+    }
+    problems.sort_by_key(|(num, _)| *num);
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(lines: &[(u16, &str)]) -> BTreeMap<u16, String> {
+        lines.iter().map(|(n, l)| (*n, l.to_string())).collect()
+    }
+
+    #[test]
+    fn test_check() {
+        let prog = program(&[
+            (10, "FOR I=1 TO 10:FOR J=1 TO 5"),
+            (20, "PRINT \"ABC"),
+            (30, "NEXT J,I"),
+            (40, "IF X THEN 100 ELSE GOSUB 10"),
+            (50, "COLOUR 15:PRINT (A+1"),
+            (60, "SPEED=1:SPIN=2:LIST 1-5"),
+            (70, "NEXT:CALL MUSIC:_TURBO ON"),
+            (80, "FOR K=0 TO 1"),
+            (90, "A$=LEFT$(B$,2)):RESUME 0"),
+        ]);
+        let problems = check(&prog);
+        assert_eq!(problems, [
+            (20, "Unterminated string".to_string()),
+            (40, "Undefined line 100 after THEN".to_string()),
+            (50, "Unknown keyword COLOUR".to_string()),
+            (50, "Unbalanced '('".to_string()),
+            (60, "Variables SPEED, SPIN are the same variable SP".to_string()),
+            (70, "NEXT without FOR".to_string()),
+            (80, "FOR K without NEXT".to_string()),
+            (90, "Unbalanced ')'".to_string()),
+        ]);
+        assert!(check(&program(&[(10, "A=1:B$=\"X\":PRINT A;B$")])).is_empty());
+    }
+}
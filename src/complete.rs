@@ -0,0 +1,155 @@
+// Tab Completion Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// 入力中の行の補完候補を作る
+//   #コマンド           ターミナルのコマンドとその引数
+//   GOTO / GOSUB の後ろ  プログラムバッファにある行番号
+//   CALL / _ の後ろ      拡張命令
+//   それ以外            MSX-BASIC の命令と関数
+//
+use crate::lexer::{self, Kind};
+use crate::tokenize;
+
+// CALL で呼ぶ拡張命令 (MSX-MUSIC, ディスク, MSX0 の IoT 命令など)
+pub const CALL_NAMES: [&str; 22] = [
+    "ANK", "AUDREG", "BGM", "FORMAT", "IOTFIND", "IOTGET", "IOTPUT", "KANJI",
+    "MEMINI", "MFILES", "MKILL", "MNAME", "MUSIC", "PAUSE", "PCMPLAY", "PCMREC",
+    "PITCH", "SYSTEM", "TEMPER", "TRANSPOSE", "TURBO", "VOICE",
+];
+
+/// コマンドの引数の補完方法
+#[derive(Debug, PartialEq)]
+pub enum CommandArgs {
+    None,
+    /// ファイル名 (- で始まればオプション)
+    File(&'static [&'static str]),
+    Words(&'static [&'static str]),
+}
+
+// コマンドごとの引数
+pub fn command_args(command: &str) -> CommandArgs {
+    match command {
+        "#load" => CommandArgs::File(&[]),
+        "#log" => CommandArgs::File(&["--time", "--raw"]),
+        "#record" | "#run_script" => CommandArgs::File(&[]),
+        "#save" => CommandArgs::File(&["--tokenized"]),
+        "#paced" | "#screen_emu" | "#mirror" => CommandArgs::Words(&["on", "off"]),
+        "#reload_to" => CommandArgs::Words(&["-y"]),
+        "#serial" => CommandArgs::Words(&["baud=", "data=", "parity=", "stop=", "flow="]),
+        _ => CommandArgs::None,
+    }
+}
+
+// 入力途中の語の先頭位置 (英数字と型宣言文字の並び)
+fn word_start(prefix: &str) -> usize {
+    prefix.char_indices()
+        .rev()
+        .find(|&(_, c)| !(c.is_ascii_alphanumeric() || c == '$'))
+        .map_or(0, |(n, c)| n + c.len_utf8())
+}
+
+// 入力した文字が小文字なら候補も小文字にする
+fn match_case(word: &str, candidate: &str) -> String {
+    if word.starts_with(|c: char| c.is_ascii_lowercase()) {
+        candidate.to_ascii_lowercase()
+    } else {
+        candidate.to_string()
+    }
+}
+
+fn starts_with_ignore_case(candidate: &str, word: &str) -> bool {
+    candidate.len() >= word.len() && candidate[..word.len()].eq_ignore_ascii_case(word)
+}
+
+//
+// BASIC の行の補完 (置き換える位置, 候補)
+// lines はプログラムバッファの行番号
+//
+pub fn complete_basic(line: &str, pos: usize, lines: &[u16]) -> (usize, Vec<String>) {
+    let prefix = &line[..pos];
+    let start = word_start(prefix);
+    let (before, word) = prefix.split_at(start);
+
+    // 行番号の位置か (数字を足してみて行番号として字句解析されるか)
+    if word.chars().all(|c| c.is_ascii_digit()) {
+        let probe = format!("{}{}0", before, word);
+        if lexer::lex(&probe).last().is_some_and(|l| l.kind == Kind::LineRef) {
+            let candidates = lines.iter()
+                .map(|n| n.to_string())
+                .filter(|n| n.starts_with(word))
+                .collect();
+            return (start, candidates);
+        }
+    }
+    if word.is_empty() || !word.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return (start, Vec::new());
+    }
+
+    // CALL の後ろは拡張命令
+    let after_call = before.ends_with('_') || lexer::lex(before).iter()
+        .rev()
+        .find(|l| l.kind != Kind::Space)
+        .and_then(|l| l.keyword())
+        .is_some_and(|k| k == "CALL");
+    let mut candidates: Vec<String> = if after_call {
+        CALL_NAMES.iter()
+            .filter(|n| starts_with_ignore_case(n, word))
+            .map(|n| match_case(word, n))
+            .collect()
+    } else {
+        tokenize::TOKENS.iter()
+            .chain(tokenize::FUNCTIONS.iter())
+            .filter(|k| k.starts_with(|c: char| c.is_ascii_alphabetic()))
+            .filter(|k| starts_with_ignore_case(k, word))
+            .map(|k| match_case(word, k))
+            .collect()
+    };
+    candidates.sort();
+    candidates.dedup();
+    (start, candidates)
+}
+
+// #コマンド名の補完
+pub fn complete_command<'a>(word: &str, commands: impl Iterator<Item = &'a String>) -> Vec<String> {
+    let mut candidates: Vec<String> = commands.filter(|c| c.starts_with(word)).cloned().collect();
+    candidates.sort();
+    candidates
+}
+
+// #コマンドの引数の補完 (ファイル名以外)
+pub fn complete_words(prefix: &str, words: &[&str]) -> (usize, Vec<String>) {
+    let start = prefix.rfind(' ').map_or(0, |n| n + 1);
+    let word = &prefix[start..];
+    let candidates = words.iter().filter(|w| w.starts_with(word)).map(|w| w.to_string()).collect();
+    (start, candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete_basic() {
+        let lines = [10, 100, 150, 200];
+        assert_eq!(complete_basic("GOTO 1", 6, &lines), (5, vec!["10".to_string(), "100".to_string(), "150".to_string()]));
+        assert_eq!(complete_basic("ON X GOSUB 10,", 14, &lines).1.len(), 4);
+        // GOTO の後ろでなければ行番号にしない
+        assert!(complete_basic("PRINT 1", 7, &lines).1.is_empty());
+
+        assert_eq!(complete_basic("10 PRI", 6, &lines), (3, vec!["PRINT".to_string()]));
+        assert_eq!(complete_basic("a$=lef", 6, &lines), (3, vec!["left$".to_string()]));
+        assert_eq!(complete_basic("CALL MU", 7, &lines), (5, vec!["MUSIC".to_string()]));
+        assert_eq!(complete_basic("_IOTG", 5, &lines), (1, vec!["IOTGET".to_string()]));
+        assert_eq!(complete_basic("PRINT \"A\" ", 10, &lines), (10, vec![]));
+    }
+
+    #[test]
+    fn test_complete_command() {
+        let commands = ["#load".to_string(), "#list".to_string(), "#save".to_string()];
+        assert_eq!(complete_command("#l", commands.iter()), ["#list", "#load"]);
+        assert_eq!(complete_words("#paced o", &["on", "off"]), (7, vec!["on".to_string(), "off".to_string()]));
+        assert_eq!(command_args("#save"), CommandArgs::File(&["--tokenized"]));
+    }
+}
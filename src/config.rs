@@ -0,0 +1,159 @@
+// Configuration File Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// ~/.config/msxterm/config.toml に機器ごとの設定 (プロファイル) を書いておく
+//
+//   default = "lab-msx0"
+//
+//   [profile.lab-msx0]
+//   target = "192.168.100.2:2223"
+//   editor = "vi"
+//   history = "lab-msx0.txt"
+//   kanji = true
+//
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::error::{self, MsxError};
+
+/// 一台分の設定 (書いていない項目は None)
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub target: Option<String>,
+    pub history: Option<String>,
+    pub editor: Option<String>,
+    pub baud: Option<u32>,
+    pub data_bits: Option<u8>,
+    pub parity: Option<String>,
+    pub stop_bits: Option<u8>,
+    pub flow: Option<String>,
+    pub raw_escape: Option<String>,
+    pub kanji: Option<bool>,
+    pub dump: Option<bool>,
+    pub paced: Option<bool>,
+    /// 色分けの組み合わせ (default, light, none)
+    pub color: Option<String>,
+}
+
+impl Profile {
+    // top に書いてある項目で上書きする
+    pub fn overlay(self, top: Profile) -> Profile {
+        Profile {
+            target: top.target.or(self.target),
+            history: top.history.or(self.history),
+            editor: top.editor.or(self.editor),
+            baud: top.baud.or(self.baud),
+            data_bits: top.data_bits.or(self.data_bits),
+            parity: top.parity.or(self.parity),
+            stop_bits: top.stop_bits.or(self.stop_bits),
+            flow: top.flow.or(self.flow),
+            raw_escape: top.raw_escape.or(self.raw_escape),
+            kanji: top.kanji.or(self.kanji),
+            dump: top.dump.or(self.dump),
+            paced: top.paced.or(self.paced),
+            color: top.color.or(self.color),
+        }
+    }
+}
+
+/// 設定ファイル
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// @名前 を指定しないときに使うプロファイル
+    pub default: Option<String>,
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> error::Result<Config> {
+        toml::from_str(text).map_err(|e| MsxError::Config(e.to_string()))
+    }
+
+    // --config で指定したファイル (ないとエラー)
+    pub fn load(path: &Path) -> error::Result<Config> {
+        let text = std::fs::read_to_string(path).map_err(|e| MsxError::File(path.display().to_string(), e))?;
+        toml::from_str(&text).map_err(|e| MsxError::Config(format!("{}: {}", path.display(), e)))
+    }
+
+    // 既定の場所のファイル (なければ空の設定)
+    pub fn load_default(path: &Path) -> error::Result<Config> {
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        Config::load(path)
+    }
+
+    //
+    // 使うプロファイルを選ぶ
+    // name が None なら default のプロファイル (なければ空)
+    //
+    pub fn select(&self, name: Option<&str>) -> error::Result<Profile> {
+        let Some(name) = name.or(self.default.as_deref()) else {
+            return Ok(Profile::default());
+        };
+        self.profile.get(name).cloned()
+            .ok_or_else(|| MsxError::Config(format!("Unknown profile: {}", name)))
+    }
+}
+
+// 設定ファイルの既定の場所
+pub fn default_path() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".config").join("msxterm").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config = Config::parse(r#"
+default = "bench1"
+
+[profile.bench1]
+target = "192.168.100.2"
+editor = "vi"
+kanji = true
+
+[profile.usb]
+target = "/dev/ttyUSB0"
+baud = 9600
+parity = "even"
+color = "light"
+"#).unwrap();
+        let bench = config.select(None).unwrap();
+        assert_eq!(bench.target.as_deref(), Some("192.168.100.2"));
+        assert_eq!(bench.kanji, Some(true));
+        let usb = config.select(Some("usb")).unwrap();
+        assert_eq!(usb.baud, Some(9600));
+        assert!(config.select(Some("nothing")).is_err());
+
+        // コマンドラインの指定が優先
+        let cli = Profile { editor: Some("emacs".to_string()), ..Profile::default() };
+        let merged = bench.overlay(cli);
+        assert_eq!(merged.editor.as_deref(), Some("emacs"));
+        assert_eq!(merged.target.as_deref(), Some("192.168.100.2"));
+
+        assert!(Config::parse("[profile.x]\nbaud_rate = 1").is_err());
+        assert!(Config::parse("").unwrap().select(None).unwrap() == Profile::default());
+
+        // 指定したファイルがないのはエラー、既定の場所にないのは空の設定
+        let missing = std::env::temp_dir().join(format!("msxterm_no_config_{}.toml", std::process::id()));
+        assert!(matches!(Config::load(&missing), Err(MsxError::File(..))));
+        assert!(Config::load_default(&missing).unwrap().profile.is_empty());
+
+        // 書式の誤りはファイル名を付けて一度だけ報告する
+        let bad = std::env::temp_dir().join(format!("msxterm_bad_config_{}.toml", std::process::id()));
+        std::fs::write(&bad, "default = 1").unwrap();
+        let message = Config::load(&bad).unwrap_err().to_string();
+        let _ = std::fs::remove_file(&bad);
+        assert!(message.starts_with(&format!("Config error: {}: ", bad.display())));
+        assert_eq!(message.matches("Config error").count(), 1);
+    }
+}
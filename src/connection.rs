@@ -1,203 +1,365 @@
-/// (TCP IP | Serial Port) Connection Module
-/// Copyright (c) 2023 Akio Setsumasa 
-/// Released under the MIT license
-/// https://github.com/akio-se/msxterm
-
+// (TCP IP | Serial Port) Connection Module
+// Copyright (c) 2023 Akio Setsumasa 
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
 use std::io::{Read, Write};
 //use serialport::SerialPort;
-use serial2::SerialPort;
+use serial2::{SerialPort, Settings, IntoSettings, CharSize, Parity, StopBits, FlowControl};
 
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpStream, ToSocketAddrs};
 use regex::Regex;
 //use std::thread;
-//use std::time::{Duration};
+use std::time::Duration;
 //use std::sync::{Arc, Mutex};
 
 
-/// Connection のトレイト定義
-pub trait Connection {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>;
-    fn write(&mut self, buf: &[u8]);
-    fn flush(&mut self) -> std::io::Result<()>;
-    fn close(&mut self) -> std::io::Result<()>;
-}
-
-/// TCP/IP コネクション
-pub struct TcpConnection {
-    stream: std::net::TcpStream,
+pub enum ConnectionType {
+    Tcp(std::net::TcpStream),
+    Serial(serial2::SerialPort),
+    /// 切断中 (理由)
+    Disconnected(String),
 }
 
-impl TcpConnection {
 
+/// シリアルポートの通信パラメータ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialParam {
+    pub baud_rate: u32,
+    pub char_size: CharSize,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
 }
 
+impl Default for SerialParam {
+    // MSX0 の USB シリアルは 115200bps 8N1 フロー制御なし
+    fn default() -> Self {
+        SerialParam {
+            baud_rate: 115_200,
+            char_size: CharSize::Bits8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
 
-impl Connection for TcpConnection {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.stream.read(buf)
+impl IntoSettings for SerialParam {
+    fn apply_to_settings(self, settings: &mut Settings) -> std::io::Result<()> {
+        settings.set_raw();
+        settings.set_baud_rate(self.baud_rate)?;
+        settings.set_char_size(self.char_size);
+        settings.set_parity(self.parity);
+        settings.set_stop_bits(self.stop_bits);
+        settings.set_flow_control(self.flow_control);
+        Ok(())
     }
-    fn write(&mut self, buf: &[u8]) {
-        self.stream.write_all(buf).expect("err"); 
+}
+
+impl std::fmt::Display for SerialParam {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parity = match self.parity {
+            Parity::None => "N",
+            Parity::Odd => "O",
+            Parity::Even => "E",
+        };
+        let flow = match self.flow_control {
+            FlowControl::None => "none",
+            FlowControl::XonXoff => "xonxoff",
+            FlowControl::RtsCts => "rtscts",
+        };
+        write!(f, "{} {}{}{} flow={}",
+            self.baud_rate, self.char_size.as_u8(), parity, self.stop_bits.as_u8(), flow)
     }
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.stream.flush()
+}
+
+impl SerialParam {
+    // パラメータ名と値を指定して一項目設定する
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.to_lowercase();
+        match key {
+            "baud" => {
+                self.baud_rate = value.parse::<u32>()
+                    .map_err(|_| format!("Bad baud rate: {}", value))?;
+            },
+            "data" => {
+                self.char_size = match value.as_str() {
+                    "5" => CharSize::Bits5,
+                    "6" => CharSize::Bits6,
+                    "7" => CharSize::Bits7,
+                    "8" => CharSize::Bits8,
+                    _ => return Err(format!("Bad data bits: {}", value)),
+                };
+            },
+            "parity" => {
+                self.parity = match value.as_str() {
+                    "none" | "n" => Parity::None,
+                    "odd" | "o" => Parity::Odd,
+                    "even" | "e" => Parity::Even,
+                    _ => return Err(format!("Bad parity: {}", value)),
+                };
+            },
+            "stop" => {
+                self.stop_bits = match value.as_str() {
+                    "1" => StopBits::One,
+                    "2" => StopBits::Two,
+                    _ => return Err(format!("Bad stop bits: {}", value)),
+                };
+            },
+            "flow" => {
+                self.flow_control = match value.as_str() {
+                    "none" => FlowControl::None,
+                    "xonxoff" | "xon" => FlowControl::XonXoff,
+                    "rtscts" | "rts" => FlowControl::RtsCts,
+                    _ => return Err(format!("Bad flow control: {}", value)),
+                };
+            },
+            _ => return Err(format!("Unknown serial parameter: {}", key)),
+        }
+        Ok(())
     }
-    fn close(&mut self) -> std::io::Result<()> {
-        self.stream.shutdown(std::net::Shutdown::Write)
+
+    // "baud=9600 parity=even flow=rtscts" 形式の引数をまとめて設定する
+    // 数字だけの項目はボーレートとみなす
+    pub fn parse_args(&mut self, args: &str) -> Result<(), String> {
+        let mut param = *self;
+        for item in args.split_whitespace() {
+            match item.split_once('=') {
+                Some((key, value)) => param.set(key, value)?,
+                None => param.set("baud", item)?,
+            }
+        }
+        *self = param;
+        Ok(())
     }
 }
 
-/// シリアルポート コネクション
-pub struct SerialConnection {
-    port: serial2::SerialPort,
+// 受信スレッドがコマンドを確認できるように read はタイムアウトさせる
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+// 再接続中も #quit できるように connect もタイムアウトさせる
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+pub fn create_connection(param:&str, serial_param: &SerialParam) -> Result<ConnectionType, ConnectionError> {
+    if is_varid_serial_port(param) {
+        let mut sp = SerialPort::open(param, *serial_param)?;
+        sp.set_read_timeout(READ_TIMEOUT)?;
+        Ok(ConnectionType::Serial(sp))
+    } else if let Some((host, port)) = parse_host_port(param) {
+        let tp = connect_tcp(&host, port)?;
+        tp.set_read_timeout(Some(READ_TIMEOUT))?;
+        Ok(ConnectionType::Tcp(tp))
+    } else {
+        Err(ConnectionError::BadTarget(param.to_string()))
+    }
 }
 
-impl Connection for SerialConnection {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.port.read(buf)
-    }
-    fn write(&mut self, buf: &[u8]) {
-        self.port.write_all(buf).expect("err")
+// ホスト名を名前解決し、得られたアドレスに順に接続を試みる
+fn connect_tcp(host: &str, port: u16) -> Result<TcpStream, ConnectionError> {
+    let addrs: Vec<SocketAddr> = match split_zone(host) {
+        Some((addr, Some(zone))) => {
+            let scope = scope_id(zone).ok_or_else(|| ConnectionError::BadTarget(host.to_string()))?;
+            vec![SocketAddr::V6(SocketAddrV6::new(addr, port, 0, scope))]
+        },
+        _ => (host, port).to_socket_addrs()?.collect(),
+    };
+    let mut last_err = std::io::Error::new(std::io::ErrorKind::NotFound, format!("No address for {}", host));
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
     }
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.port.flush()
+    Err(ConnectionError::Io(last_err))
+}
+
+// IPv6 のゾーン (fe80::1%eth0 の eth0) をスコープ ID にする
+// 数字はそのまま、それ以外はインターフェース名とみなす
+fn scope_id(zone: &str) -> Option<u32> {
+    if let Ok(id) = zone.parse::<u32>() {
+        return Some(id);
     }
-    fn close(&mut self) -> std::io::Result<()> {
-        Ok(())
+    #[cfg(unix)]
+    {
+        let name = std::ffi::CString::new(zone).ok()?;
+        // SAFETY: name は NUL 終端された文字列
+        let id = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if id != 0 {
+            return Some(id);
+        }
     }
+    None
 }
 
-pub enum ConnectionType {
-    Tcp(std::net::TcpStream),
-    Serial(serial2::SerialPort),
-    BadParam(String),
+// read のタイムアウトによるエラーかどうか
+pub fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock)
 }
 
+/// 通信エラー
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// 接続先の指定が不正
+    BadTarget(String),
+    /// 切断中
+    NotConnected(String),
+    /// シリアルポート以外への操作
+    NotSerial,
+    /// 受信スレッドが停止している
+    ReceiverStopped,
+    /// 入出力エラー
+    Io(std::io::Error),
+}
 
-pub fn create_connection(param:&str) -> ConnectionType {
-    if is_valid_ip_port(param) {
-        let tp = std::net::TcpStream::connect(param);
-        match tp {
-            Ok(t) => ConnectionType::Tcp(t),
-            Err(e) => ConnectionType::BadParam(e.to_string())
-        }
-    } else if is_varid_serial_port(param) {
-        let sp = SerialPort::open(param,115_200 );
-        match sp {
-            Ok(s) => ConnectionType::Serial(s),
-            Err(e) => ConnectionType::BadParam(e.to_string())
+impl std::fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionError::BadTarget(t) => write!(f, "Bad target (IP:Port or Serial Port): {}", t),
+            ConnectionError::NotConnected(e) => write!(f, "{}", e),
+            ConnectionError::NotSerial => write!(f, "Not a serial connection"),
+            ConnectionError::ReceiverStopped => write!(f, "Receive thread is stopped"),
+            ConnectionError::Io(e) => write!(f, "{}", e),
         }
-    } else {
-        ConnectionType::BadParam("ParamError".to_string())
     }
 }
 
+impl std::error::Error for ConnectionError {}
 
-
-enum ConnectionError {
-
+impl From<std::io::Error> for ConnectionError {
+    fn from(e: std::io::Error) -> Self {
+        ConnectionError::Io(e)
+    }
 }
 
 impl ConnectionType {
 
-    pub fn write(&mut self, buff: &[u8]) -> Result<(), String> {
+    // シリアルポートの通信パラメータを変更する
+    pub fn set_serial_param(&mut self, serial_param: &SerialParam) -> Result<(), ConnectionError> {
         match self {
-            ConnectionType::Tcp(stream) => {
-                match stream.write_all(buff) {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(e.to_string()),
-                }
-            },
-            ConnectionType::Serial(serialport) => {
-                match serialport.write_all(buff) {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(e.to_string()),
-                }
+            ConnectionType::Serial(sp) => {
+                let mut settings = sp.get_configuration()?;
+                serial_param.apply_to_settings(&mut settings)?;
+                sp.set_configuration(&settings)?;
+                Ok(())
             },
-            ConnectionType::BadParam(e) => {
-                Err(e.to_string())
-            }
+            ConnectionType::Tcp(_) => Err(ConnectionError::NotSerial),
+            ConnectionType::Disconnected(e) => Err(ConnectionError::NotConnected(e.to_string())),
         }
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, String> {
+    // 受信スレッド用に複製する
+    pub fn try_clone(&self) -> Result<ConnectionType, ConnectionError> {
         match self {
-            ConnectionType::Tcp(stream) => {
-                match stream.read(buf) {
-                    Ok(size) => Ok(size),
-                    Err(e) => Err(e.to_string()),
-                }
-            },
-            ConnectionType::Serial(serialport) => {
-                match serialport.read(buf) {
-                    Ok(size) => Ok(size),
-                    Err(e) => Err(e.to_string()),
-                }
-            },
-            ConnectionType::BadParam(e) => {
-                Err(e.to_string())
-            }
+            ConnectionType::Tcp(stream) => Ok(ConnectionType::Tcp(stream.try_clone()?)),
+            ConnectionType::Serial(serialport) => Ok(ConnectionType::Serial(serialport.try_clone()?)),
+            ConnectionType::Disconnected(e) => Err(ConnectionError::NotConnected(e.to_string())),
         }
     }
 
-    pub fn flush(&mut self) -> Result<(), String>  {
+    pub fn write(&mut self, buff: &[u8]) -> Result<(), ConnectionError> {
         match self {
-            ConnectionType::Tcp(stream) => {
-                match stream.flush() {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(e.to_string()),
-                }
-            },
-            ConnectionType::Serial(sp) => {
-                match sp.flush() {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(e.to_string()),
-                }
-            },
-            ConnectionType::BadParam(e) => {
-                Err(e.to_string())
-            }
+            ConnectionType::Tcp(stream) => Ok(stream.write_all(buff)?),
+            ConnectionType::Serial(serialport) => Ok(serialport.write_all(buff)?),
+            ConnectionType::Disconnected(e) => Err(ConnectionError::NotConnected(e.to_string())),
         }
     }
 
-    pub fn close(&mut self) -> Result<(), String> {
+    // タイムアウトを判別できるように io::Error のまま返す
+    pub fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         match self {
-            ConnectionType::Tcp(stream) => {
-                match stream.shutdown(std::net::Shutdown::Write) {
-                    Ok(_) => Ok(()),
-                    Err(e) => Err(e.to_string())
-                }
-            },
-            ConnectionType::Serial(sp) => {
-                Ok(())
-            },
-            ConnectionType::BadParam(e) => {
-                Err(e.to_string())
+            ConnectionType::Tcp(stream) => stream.read(buf),
+            ConnectionType::Serial(serialport) => serialport.read(buf),
+            ConnectionType::Disconnected(e) => {
+                Err(std::io::Error::new(std::io::ErrorKind::NotConnected, e.to_string()))
             }
         }
     }
+
+    pub fn flush(&mut self) -> Result<(), ConnectionError>  {
+        match self {
+            ConnectionType::Tcp(stream) => Ok(stream.flush()?),
+            ConnectionType::Serial(sp) => Ok(sp.flush()?),
+            ConnectionType::Disconnected(e) => Err(ConnectionError::NotConnected(e.to_string())),
+        }
+    }
+
+    pub fn close(&mut self) -> Result<(), ConnectionError> {
+        match self {
+            ConnectionType::Tcp(stream) => Ok(stream.shutdown(std::net::Shutdown::Both)?),
+            ConnectionType::Serial(_) => Ok(()),
+            ConnectionType::Disconnected(e) => Err(ConnectionError::NotConnected(e.to_string())),
+        }
+    }
 }
 
 
-// 入力された文字列が IPアドレス:Port を示す文字列かどうかチェック
-fn is_valid_ip_port(input: &str) -> bool {
-    let parts: Vec<&str> = input.split(':').collect();
-    // Check that there are two parts
-    if parts.len() != 2 {
-        return false;
+// MSX0 の TCP/IP 接続のデフォルトポート
+pub const DEFAULT_PORT: u16 = 2223;
+
+// IPv6 アドレスとゾーン (%eth0 など) に分ける
+fn split_zone(addr: &str) -> Option<(Ipv6Addr, Option<&str>)> {
+    match addr.split_once('%') {
+        Some((ip, zone)) => {
+            let valid = !zone.is_empty()
+                && zone.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+            if !valid {
+                return None;
+            }
+            Some((ip.parse::<Ipv6Addr>().ok()?, Some(zone)))
+        },
+        None => Some((addr.parse::<Ipv6Addr>().ok()?, None)),
     }
-    // Check that the IP address part is valid
-    match parts[0].parse::<IpAddr>() {
-        Ok(IpAddr::V4(_)) => (),
-        Ok(IpAddr::V6(_)) => (),
-        _ => return false,
+}
+
+// 入力された文字列を ホスト と Port に分解する
+// host / host:port / IPv4:port / [IPv6]:port / IPv6 を受け付け、
+// Port を省略した場合は DEFAULT_PORT とする
+// IPv6 にはゾーン (fe80::1%eth0) も付けられる
+fn parse_host_port(input: &str) -> Option<(String, u16)> {
+    let (host, port) = if let Some(rest) = input.strip_prefix('[') {
+        // [IPv6]:port
+        let (addr, rest) = rest.split_once(']')?;
+        split_zone(addr)?;
+        match rest {
+            "" => (addr, None),
+            _ => (addr, Some(rest.strip_prefix(':')?)),
+        }
+    } else if split_zone(input).is_some() {
+        // Port なしの IPv6
+        (input, None)
+    } else {
+        match input.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (input, None),
+        }
+    };
+    let port = match port {
+        Some(p) => p.parse::<u16>().ok()?,
+        None => DEFAULT_PORT,
+    };
+    if host.parse::<IpAddr>().is_ok() || split_zone(host).is_some() || is_valid_hostname(host) {
+        Some((host.to_string(), port))
+    } else {
+        None
     }
-    // Check that the port number part is valid
-    match parts[1].parse::<u16>() {
-        Ok(_) => (),
-        _ => return false,
+}
+
+// ホスト名として使える文字列かどうかチェック
+// 数字だけのラベルで構成されるものは IPv4 の書き損じとみなす
+fn is_valid_hostname(host: &str) -> bool {
+    if host.is_empty() || host.len() > 253 {
+        return false;
     }
-    true
+    let labels: Vec<&str> = host.trim_end_matches('.').split('.').collect();
+    let valid = labels.iter().all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    });
+    valid && !labels.iter().all(|label| label.chars().all(|c| c.is_ascii_digit()))
 }
 
 // 入力された文字列がシリアルポートを示す文字列かどうかチェック
@@ -222,47 +384,78 @@ mod tests {
 
     #[test]
     fn test_ip_port() {
-        assert_eq!(is_valid_ip_port("192.168.128.7:2223"), true);
-        assert_eq!(is_valid_ip_port("192.234.456.122:2223"), false);
-        assert_eq!(is_valid_ip_port("192.234.156.122:70223"), false);
-        assert_eq!(is_valid_ip_port("localhot:2223"), false);
+        let hp = |h: &str, p: u16| Some((h.to_string(), p));
+        assert_eq!(parse_host_port("192.168.128.7:2223"), hp("192.168.128.7", 2223));
+        assert_eq!(parse_host_port("192.168.128.7"), hp("192.168.128.7", DEFAULT_PORT));
+        assert_eq!(parse_host_port("192.234.456.122:2223"), None);
+        assert_eq!(parse_host_port("192.234.156.122:70223"), None);
+        assert_eq!(parse_host_port("localhost:2223"), hp("localhost", 2223));
+        assert_eq!(parse_host_port("msx0.local"), hp("msx0.local", DEFAULT_PORT));
+        assert_eq!(parse_host_port("[fe80::1]:2224"), hp("fe80::1", 2224));
+        assert_eq!(parse_host_port("[fe80::1]"), hp("fe80::1", DEFAULT_PORT));
+        assert_eq!(parse_host_port("fe80::1"), hp("fe80::1", DEFAULT_PORT));
+        assert_eq!(parse_host_port("[fe80::1%eth0]:2223"), hp("fe80::1%eth0", 2223));
+        assert_eq!(parse_host_port("fe80::1%2"), hp("fe80::1%2", DEFAULT_PORT));
+        assert_eq!(parse_host_port("[fe80::1%]:2223"), None);
+        assert_eq!(parse_host_port("[msx0.local]:2223"), None);
+        assert_eq!(parse_host_port("msx 0:2223"), None);
+        assert_eq!(parse_host_port(""), None);
     }
 
     #[test]
     fn test_serial_path() {
         #[cfg(target_os = "windows")]
         {
-            assert_eq!(is_varid_serial_port("COM3"), true);
-            assert_eq!(is_varid_serial_port("com1"), false);
+            assert!(is_varid_serial_port("COM3"));
+            assert!(!is_varid_serial_port("com1"));
         }
         #[cfg(target_os = "linux")]
         {
-            assert_eq!(is_varid_serial_port("/dev/ttyS1"), true);
-            assert_eq!(is_varid_serial_port("/dev/ttyUSB1"), true);
+            assert!(is_varid_serial_port("/dev/ttyS1"));
+            assert!(is_varid_serial_port("/dev/ttyUSB1"));
         }
         #[cfg(target_os = "macos")]
         {
-            assert_eq!(is_varid_serial_port("/dev/tty.usbserial-559B0204231"), true);
-            assert_eq!(is_varid_serial_port("/dev/cu.usbserial-559B0204231"), true);    
+            assert!(is_varid_serial_port("/dev/tty.usbserial-559B0204231"));
+            assert!(is_varid_serial_port("/dev/cu.usbserial-559B0204231"));    
         }
     }
 
+    #[test]
+    fn test_serial_param() {
+        let mut sp = SerialParam::default();
+        assert_eq!(sp.to_string(), "115200 8N1 flow=none");
+        sp.parse_args("9600 data=7 parity=even stop=2 flow=rtscts").unwrap();
+        assert_eq!(sp.to_string(), "9600 7E2 flow=rtscts");
+        assert!(sp.parse_args("baud=1200 parity=mark").is_err());
+        // エラー時は変更しない
+        assert_eq!(sp.baud_rate, 9600);
+        assert!(sp.parse_args("speed=1200").is_err());
+    }
+
     #[test]
     fn test_create_con() {
 //      let mut contype = create_connection("/dev/cu.usbserial-569C0128081");
-        let contype = create_connection("192.168.128.13:2223");
-        match contype {
-            ConnectionType::Tcp( mut ts) => {
-                ts.write(b"Test TCP\r").expect("TCP/IP write err");
-
-            },
-            ConnectionType::Serial(sr) => {
-                sr.write(b"Test Serial\r").expect("serial write error");
-            },
-            ConnectionType::BadParam(e)=> {
-                println!("error {}", e);
+        let addr = crate::sim::Simulator::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+        let mut contype = create_connection(&addr.to_string(), &SerialParam::default()).unwrap();
+        assert!(matches!(contype, ConnectionType::Tcp(_)));
+        contype.write(b"Test TCP\r").expect("TCP/IP write err");
+
+        let mut buf = [0_u8; 64];
+        let mut recv = Vec::new();
+        while !recv.ends_with(b"Ok\r\n") {
+            match contype.read(&mut buf) {
+                Ok(size) => recv.extend_from_slice(&buf[..size]),
+                Err(e) if is_timeout(&e) => continue,
+                Err(e) => panic!("{}", e),
             }
         }
+        assert_eq!(recv, b"Test TCP\r\nSyntax error\r\nOk\r\n");
+
+        let contype = create_connection("192.234.456.122:2223", &SerialParam::default());
+        assert!(matches!(contype, Err(ConnectionError::BadTarget(_))));
+        assert_eq!(scope_id("3"), Some(3));
+        assert_eq!(scope_id("no-such-interface0"), None);
     }
 
 }
@@ -0,0 +1,169 @@
+// MSX Program Diff Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// ターミナル側のプログラムバッファと MSX 側の LIST の比較
+//
+use std::collections::BTreeMap;
+use crate::highlight::{self, Scheme};
+use crate::tokenize;
+
+/// 一行分の違い
+#[derive(Debug, Clone, PartialEq)]
+pub enum LineDiff {
+    /// ターミナル側にだけある行
+    Added(u16, String),
+    /// MSX 側にだけある行
+    Removed(u16, String),
+    /// 内容が違う行 (ターミナル側, MSX 側)
+    Changed(u16, String, String),
+}
+
+impl LineDiff {
+    // MSX 側をターミナル側に合わせるために送る行
+    // 削除は行番号だけを送る
+    pub fn sync_line(&self) -> String {
+        match self {
+            LineDiff::Added(num, line) | LineDiff::Changed(num, line, _) => format!("{} {}", num, line),
+            LineDiff::Removed(num, _) => num.to_string(),
+        }
+    }
+}
+
+// LIST の出力をプログラムバッファと同じ形にする
+pub fn parse_listing(listing: &[String]) -> BTreeMap<u16, String> {
+    let mut program = BTreeMap::new();
+    for line in listing {
+        let mut iter = line.splitn(2, ' ');
+        if let (Some(Ok(num)), Some(body)) = (iter.next().map(|n| n.parse::<u16>()), iter.next()) {
+            program.insert(num, body.trim().to_string());
+        }
+    }
+    program
+}
+
+// 比較用に MSX の LIST で表示される形にする
+// 中間言語にできない行は文字列の外を大文字にするだけ
+fn normalize(line: &str) -> String {
+    match tokenize::list_form(line.trim().as_bytes()) {
+        Ok(text) => String::from_utf8_lossy(&text).to_string(),
+        Err(_) => upper_outside_quotes(line),
+    }
+}
+
+fn upper_outside_quotes(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut quoted = false;
+    for c in line.trim().chars() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        if quoted {
+            out.push(c);
+        } else {
+            out.extend(c.to_uppercase());
+        }
+    }
+    out
+}
+
+pub fn diff_program(local: &BTreeMap<u16, String>, remote: &BTreeMap<u16, String>) -> Vec<LineDiff> {
+    let mut diffs = Vec::new();
+    let mut numbers: Vec<u16> = local.keys().chain(remote.keys()).copied().collect();
+    numbers.sort_unstable();
+    numbers.dedup();
+    for num in numbers {
+        match (local.get(&num), remote.get(&num)) {
+            (Some(l), None) => diffs.push(LineDiff::Added(num, l.clone())),
+            (None, Some(r)) => diffs.push(LineDiff::Removed(num, r.clone())),
+            (Some(l), Some(r)) if normalize(l) != normalize(r) => {
+                diffs.push(LineDiff::Changed(num, l.clone(), r.clone()))
+            },
+            _ => {},
+        }
+    }
+    diffs
+}
+
+// 追加・削除・変更の行の色
+const ADDED: &str = "\x1b[32m";
+const REMOVED: &str = "\x1b[31m";
+const CHANGED: &str = "\x1b[33m";
+
+// 表示する行 (色なしの scheme では色を付けない)
+fn diff_lines(diffs: &[LineDiff], scheme: &Scheme) -> Vec<String> {
+    let paint = |color: &str, text: String| {
+        highlight::colored(if *scheme == Scheme::NONE { "" } else { color }, &text)
+    };
+    let mut lines = Vec::new();
+    for diff in diffs {
+        match diff {
+            LineDiff::Added(num, line) => lines.push(paint(ADDED, format!("+ {} {}", num, line))),
+            LineDiff::Removed(num, line) => lines.push(paint(REMOVED, format!("- {} {}", num, line))),
+            LineDiff::Changed(num, local, remote) => {
+                lines.push(format!("{} {}", paint(CHANGED, format!("~ {}", num)), paint(REMOVED, format!("- {}", remote))));
+                lines.push(format!("{} {}", paint(CHANGED, format!("~ {}", num)), paint(ADDED, format!("+ {}", local))));
+            },
+        }
+    }
+    lines
+}
+
+// 色付きで表示する
+pub fn print_diff(diffs: &[LineDiff], scheme: &Scheme) {
+    for line in diff_lines(diffs, scheme) {
+        println!("{}", line);
+    }
+    let count = |f: fn(&LineDiff) -> bool| diffs.iter().filter(|d| f(d)).count();
+    println!("{} added, {} removed, {} changed",
+        count(|d| matches!(d, LineDiff::Added(..))),
+        count(|d| matches!(d, LineDiff::Removed(..))),
+        count(|d| matches!(d, LineDiff::Changed(..))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_program() {
+        let local: BTreeMap<u16, String> = [
+            (10, "print \"Hello\""),
+            (20, "GOTO 10"),
+            (30, "END"),
+        ].iter().map(|(n, l)| (*n, l.to_string())).collect();
+        let remote = parse_listing(&[
+            "10 PRINT \"Hello\"".to_string(),
+            "20 GOTO 20".to_string(),
+            "40 STOP".to_string(),
+        ]);
+        let diffs = diff_program(&local, &remote);
+        assert_eq!(diffs, vec![
+            LineDiff::Changed(20, "GOTO 10".to_string(), "GOTO 20".to_string()),
+            LineDiff::Added(30, "END".to_string()),
+            LineDiff::Removed(40, "STOP".to_string()),
+        ]);
+        let sync: Vec<String> = diffs.iter().map(|d| d.sync_line()).collect();
+        assert_eq!(sync, ["20 GOTO 10", "30 END", "40"]);
+        assert_eq!(diff_lines(&diffs, &Scheme::NONE), ["~ 20 - GOTO 20", "~ 20 + GOTO 10", "+ 30 END", "- 40 STOP"]);
+        assert_eq!(diff_lines(&diffs, &Scheme::DEFAULT)[2], "\x1b[32m+ 30 END\x1b[0m");
+
+        // 文字列の中の大文字小文字は区別する
+        let remote = parse_listing(&["10 PRINT \"HELLO\"".to_string()]);
+        assert_eq!(diff_program(&local, &remote)[0], LineDiff::Changed(10, "print \"Hello\"".to_string(), "PRINT \"HELLO\"".to_string()));
+
+        // LIST で書き換えられる書き方は同じ行とみなす
+        let local: BTreeMap<u16, String> = [
+            (10, "?\"A\""),
+            (20, "goto 0100"),
+            (30, "a=1.0"),
+        ].iter().map(|(n, l)| (*n, l.to_string())).collect();
+        let remote = parse_listing(&[
+            "10 PRINT\"A\"".to_string(),
+            "20 GOTO 100".to_string(),
+            "30 A=1!".to_string(),
+        ]);
+        assert!(diff_program(&local, &remote).is_empty());
+    }
+}
@@ -0,0 +1,73 @@
+// MSX Term Error Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+use std::fmt;
+use rustyline::error::ReadlineError;
+use crate::connection::ConnectionError;
+
+/// ターミナル全体のエラー
+/// コマンドの実行結果として返し、プロンプトに表示して処理を続ける
+#[derive(Debug)]
+pub enum MsxError {
+    /// 通信エラー
+    Connection(ConnectionError),
+    /// 文字コード変換エラー
+    Codec(String),
+    /// ファイル入出力エラー (パス, 原因)
+    File(String, std::io::Error),
+    /// ターミナルコマンドの書式エラー
+    Command(String),
+    /// 行エディタのエラー
+    Editor(ReadlineError),
+    /// 端末制御のエラー
+    Terminal(std::io::Error),
+    /// MSX からの応答待ちのタイムアウト (待っていたもの)
+    Timeout(String),
+    /// MSX 側が返したエラー (送った行, エラーメッセージ)
+    Device(String, String),
+    /// 行番号なしソースの変換エラー
+    Source(String),
+    /// 設定ファイルのエラー
+    Config(String),
+    /// 再生するファイルの形式エラー
+    Replay(String),
+    /// スクリプトの実行エラー (場所を含む)
+    Script(String),
+}
+
+pub type Result<T> = std::result::Result<T, MsxError>;
+
+impl fmt::Display for MsxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MsxError::Connection(e) => write!(f, "Connection error: {}", e),
+            MsxError::Codec(e) => write!(f, "Codec error: {}", e),
+            MsxError::File(path, e) => write!(f, "File error: {}: {}", path, e),
+            MsxError::Command(e) => write!(f, "Command error: {}", e),
+            MsxError::Editor(e) => write!(f, "Editor error: {}", e),
+            MsxError::Terminal(e) => write!(f, "Terminal error: {}", e),
+            MsxError::Timeout(e) => write!(f, "Timeout: waiting for {}", e),
+            MsxError::Device(line, e) => write!(f, "MSX error: {} ({})", e, line),
+            MsxError::Source(e) => write!(f, "Source error: {}", e),
+            MsxError::Config(e) => write!(f, "Config error: {}", e),
+            MsxError::Replay(e) => write!(f, "Replay error: {}", e),
+            MsxError::Script(e) => write!(f, "Script error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MsxError {}
+
+impl From<ConnectionError> for MsxError {
+    fn from(e: ConnectionError) -> Self {
+        MsxError::Connection(e)
+    }
+}
+
+impl From<ReadlineError> for MsxError {
+    fn from(e: ReadlineError) -> Self {
+        MsxError::Editor(e)
+    }
+}
@@ -0,0 +1,91 @@
+// Line Editor Helper Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// rustyline に組み込む補助機能 (入力中の行の色分けと補完)
+//
+use std::borrow::Cow;
+use std::collections::HashMap;
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use crate::complete::{self, CommandArgs};
+use crate::highlight;
+
+/// msxterm のラインエディタ
+pub type MsxEditor = Editor<MsxHelper, DefaultHistory>;
+
+pub struct MsxHelper {
+    /// ターミナルのコマンドと説明 (Msxterm の t_com)
+    pub commands: HashMap<String, String>,
+    /// プログラムバッファの行番号 (入力のたびに更新する)
+    pub lines: Vec<u16>,
+    scheme: highlight::Scheme,
+    files: FilenameCompleter,
+}
+
+impl MsxHelper {
+    pub fn new(commands: HashMap<String, String>, scheme: highlight::Scheme) -> MsxHelper {
+        MsxHelper { commands, lines: Vec::new(), scheme, files: FilenameCompleter::new() }
+    }
+}
+
+fn pairs(candidates: Vec<String>) -> Vec<Pair> {
+    candidates.into_iter().map(|c| Pair { display: c.clone(), replacement: c }).collect()
+}
+
+impl Completer for MsxHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let prefix = &line[..pos];
+        if !prefix.starts_with('#') {
+            let (start, candidates) = complete::complete_basic(line, pos, &self.lines);
+            return Ok((start, pairs(candidates)));
+        }
+        let Some((command, _)) = prefix.split_once(' ') else {
+            // 候補の一覧にはコマンドの説明も表示する
+            let candidates = complete::complete_command(prefix, self.commands.keys())
+                .into_iter()
+                .map(|c| Pair { display: format!("{:<14}{}", c, self.commands[&c]), replacement: c })
+                .collect();
+            return Ok((0, candidates));
+        };
+        let word = prefix.rsplit(' ').next().unwrap_or("");
+        match complete::command_args(command) {
+            CommandArgs::File(options) if word.starts_with('-') => {
+                let (start, candidates) = complete::complete_words(prefix, options);
+                Ok((start, pairs(candidates)))
+            },
+            CommandArgs::File(_) => self.files.complete(line, pos, ctx),
+            CommandArgs::Words(words) => {
+                let (start, candidates) = complete::complete_words(prefix, words);
+                Ok((start, pairs(candidates)))
+            },
+            CommandArgs::None => Ok((pos, Vec::new())),
+        }
+    }
+}
+
+impl Highlighter for MsxHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        Cow::Owned(highlight::highlight_input(line, &self.scheme))
+    }
+
+    // 一文字ごとに色を付け直す
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        true
+    }
+}
+
+impl Hinter for MsxHelper {
+    type Hint = String;
+}
+
+impl Validator for MsxHelper {}
+
+impl Helper for MsxHelper {}
@@ -0,0 +1,167 @@
+// MSX-BASIC Syntax Highlight Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// #list の表示と入力中の行を字句ごとに色分けする
+//
+use crate::lexer::{self, Kind};
+
+const RESET: &str = "\x1b[0m";
+
+/// 色の組み合わせ (空文字列は色を付けない)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scheme {
+    /// 行番号と飛び先の行番号
+    pub line: &'static str,
+    pub keyword: &'static str,
+    pub string: &'static str,
+    pub number: &'static str,
+    pub operator: &'static str,
+    pub remark: &'static str,
+    /// ターミナルのコマンド (#list など)
+    pub command: &'static str,
+}
+
+impl Scheme {
+    pub const DEFAULT: Scheme = Scheme {
+        line: "\x1b[36m",
+        keyword: "\x1b[33m",
+        string: "\x1b[32m",
+        number: "\x1b[35m",
+        operator: "\x1b[31m",
+        remark: "\x1b[90m",
+        command: "\x1b[1;34m",
+    };
+    // 背景が白い端末用
+    pub const LIGHT: Scheme = Scheme {
+        line: "\x1b[34m",
+        keyword: "\x1b[1;35m",
+        string: "\x1b[32m",
+        number: "\x1b[36m",
+        operator: "\x1b[31m",
+        remark: "\x1b[2m",
+        command: "\x1b[1;34m",
+    };
+    pub const NONE: Scheme = Scheme {
+        line: "", keyword: "", string: "", number: "", operator: "", remark: "", command: "",
+    };
+
+    pub fn by_name(name: &str) -> Option<Scheme> {
+        match name {
+            "default" => Some(Scheme::DEFAULT),
+            "light" => Some(Scheme::LIGHT),
+            "none" => Some(Scheme::NONE),
+            _ => None,
+        }
+    }
+
+    fn color(&self, kind: Kind, text: &str) -> &'static str {
+        match kind {
+            // REM と ' はコメントと同じ色
+            Kind::Keyword if text == "'" || text.eq_ignore_ascii_case("REM") => self.remark,
+            Kind::Keyword | Kind::Function => self.keyword,
+            Kind::Str => self.string,
+            Kind::Number => self.number,
+            Kind::LineRef => self.line,
+            Kind::Operator => self.operator,
+            Kind::Remark => self.remark,
+            _ => "",
+        }
+    }
+}
+
+impl Default for Scheme {
+    fn default() -> Self {
+        Scheme::DEFAULT
+    }
+}
+
+fn paint(out: &mut String, color: &str, text: &str) {
+    if color.is_empty() {
+        out.push_str(text);
+    } else {
+        out.push_str(color);
+        out.push_str(text);
+        out.push_str(RESET);
+    }
+}
+
+// color で色を付けた text
+pub fn colored(color: &str, text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 8);
+    paint(&mut out, color, text);
+    out
+}
+
+// 行番号の後ろを色分けする
+pub fn highlight_basic(text: &str, scheme: &Scheme) -> String {
+    let mut out = String::with_capacity(text.len() * 2);
+    for lexeme in lexer::lex(text) {
+        paint(&mut out, scheme.color(lexeme.kind, lexeme.text), lexeme.text);
+    }
+    out
+}
+
+// #list の一行
+pub fn highlight_line(num: &str, text: &str, scheme: &Scheme) -> String {
+    let mut out = String::new();
+    paint(&mut out, scheme.line, num);
+    out.push(' ');
+    out.push_str(&highlight_basic(text, scheme));
+    out
+}
+
+//
+// 入力中の行
+// # で始まればターミナルのコマンド、数字で始まればプログラムの行、
+// それ以外は MSX への直接命令として色分けする
+//
+pub fn highlight_input(line: &str, scheme: &Scheme) -> String {
+    let mut out = String::with_capacity(line.len() * 2);
+    if line.starts_with('#') {
+        let len = line.find(' ').unwrap_or(line.len());
+        paint(&mut out, scheme.command, &line[..len]);
+        out.push_str(&line[len..]);
+        return out;
+    }
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    let rest = &line[indent..];
+    let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    if digits == 0 {
+        return highlight_basic(line, scheme);
+    }
+    out.push_str(&line[..indent]);
+    paint(&mut out, scheme.line, &rest[..digits]);
+    out.push_str(&highlight_basic(&rest[digits..], scheme));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // エスケープシーケンスを取り除く
+    fn strip(s: &str) -> String {
+        let re = regex::Regex::new("\x1b\\[[0-9;]*m").unwrap();
+        re.replace_all(s, "").to_string()
+    }
+
+    #[test]
+    fn test_highlight() {
+        let scheme = Scheme::DEFAULT;
+        let line = "IF A$=\"X\" THEN 100 ELSE PRINT 1+2:REM end";
+        assert_eq!(strip(&highlight_basic(line, &scheme)), line);
+        assert_eq!(highlight_basic("GOTO 10", &scheme), "\x1b[33mGOTO\x1b[0m \x1b[36m10\x1b[0m");
+        assert_eq!(highlight_basic("?\"A\"'x", &scheme),
+            "\x1b[33m?\x1b[0m\x1b[32m\"A\"\x1b[0m\x1b[90m'\x1b[0m\x1b[90mx\x1b[0m");
+
+        assert_eq!(highlight_input("#list 10-20", &scheme), "\x1b[1;34m#list\x1b[0m 10-20");
+        assert_eq!(highlight_input("10 X=1", &scheme), "\x1b[36m10\x1b[0m X\x1b[31m=\x1b[0m\x1b[35m1\x1b[0m");
+        assert_eq!(strip(&highlight_input(" 20 PRINT \"あ", &scheme)), " 20 PRINT \"あ");
+        // 色なし
+        assert_eq!(highlight_line("10", line, &Scheme::NONE), format!("10 {}", line));
+        assert_eq!(colored(Scheme::DEFAULT.line, "10"), "\x1b[36m10\x1b[0m");
+        assert_eq!(colored(Scheme::NONE.line, "10"), "10");
+    }
+}
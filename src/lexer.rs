@@ -0,0 +1,246 @@
+// MSX-BASIC Lexer Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// プログラムバッファの一行 (行番号の後ろ) を字句に分ける
+// 命令の切り出し方は tokenize と同じにする
+//
+use crate::tokenize;
+
+/// 字句の種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// 命令 (? も含む)
+    Keyword,
+    /// 0xFF で始まる関数
+    Function,
+    /// 演算子 + - * / ^ \ = < >
+    Operator,
+    /// 数値定数 (&H &O &B も含む)
+    Number,
+    /// GOTO などの後ろの行番号
+    LineRef,
+    /// "文字列"
+    Str,
+    /// REM や ' の後ろ
+    Remark,
+    /// DATA の後ろ
+    Data,
+    /// 変数名
+    Name,
+    /// その他の記号
+    Punct,
+    Space,
+}
+
+/// 字句 (text を全部つなぐと元の行になる)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Lexeme<'a> {
+    pub kind: Kind,
+    pub text: &'a str,
+}
+
+impl Lexeme<'_> {
+    // 命令名 (大文字)
+    pub fn keyword(&self) -> Option<String> {
+        match self.kind {
+            Kind::Keyword | Kind::Function => Some(self.text.to_ascii_uppercase()),
+            _ => None,
+        }
+    }
+}
+
+// 先頭から条件に合う部分の長さ (バイト数)
+fn span<F: Fn(char) -> bool>(s: &str, f: F) -> usize {
+    s.char_indices().find(|(_, c)| !f(*c)).map_or(s.len(), |(n, _)| n)
+}
+
+// 数値定数の長さ
+fn number_len(s: &str) -> usize {
+    let b = s.as_bytes();
+    if b[0] == b'&' {
+        let radix = match b.get(1).map(|c| c.to_ascii_uppercase()) {
+            Some(b'H') => 16,
+            Some(b'O') => 8,
+            Some(b'B') => 2,
+            _ => return 0,
+        };
+        return 2 + span(&s[2..], |c| c.is_digit(radix));
+    }
+    let mut n = span(s, |c| c.is_ascii_digit() || c == '.');
+    // 指数部
+    if let Some(&m) = b.get(n) {
+        if matches!(m.to_ascii_uppercase(), b'E' | b'D') {
+            let mut j = n + 1;
+            if matches!(b.get(j), Some(b'+') | Some(b'-')) {
+                j += 1;
+            }
+            let digits = span(&s[j..], |c| c.is_ascii_digit());
+            if digits > 0 {
+                n = j + digits;
+            }
+        }
+    }
+    if matches!(b.get(n), Some(b'%') | Some(b'!') | Some(b'#')) {
+        n += 1;
+    }
+    n
+}
+
+pub fn lex(line: &str) -> Vec<Lexeme<'_>> {
+    let mut out = Vec::new();
+    let mut rest = line;
+    // 行番号を取る命令の後ろ
+    let mut line_number = false;
+    // ERL の後ろ (比較演算子の次の数値が行番号になる)
+    let mut erl = false;
+    fn push<'a>(out: &mut Vec<Lexeme<'a>>, kind: Kind, len: usize, rest: &mut &'a str) {
+        let (text, r) = rest.split_at(len);
+        *rest = r;
+        out.push(Lexeme { kind, text });
+    }
+    while let Some(c) = rest.chars().next() {
+        if c == '"' {
+            let len = rest[1..].find('"').map_or(rest.len(), |n| n + 2);
+            push(&mut out, Kind::Str, len, &mut rest);
+            line_number = false;
+            erl = false;
+            continue;
+        }
+        if c == '\'' {
+            push(&mut out, Kind::Keyword, 1, &mut rest);
+            let len = rest.len();
+            push(&mut out, Kind::Remark, len, &mut rest);
+            break;
+        }
+        if c == '?' {
+            push(&mut out, Kind::Keyword, 1, &mut rest);
+            line_number = false;
+            erl = false;
+            continue;
+        }
+        if c.is_ascii_alphabetic() {
+            if let Some((code, keyword)) = tokenize::match_keyword(rest.as_bytes()) {
+                let kind = if code[0] == 0xff { Kind::Function } else { Kind::Keyword };
+                push(&mut out, kind, keyword.len(), &mut rest);
+                line_number = tokenize::LINE_NUMBER_TOKENS.contains(&keyword);
+                erl = keyword == "ERL";
+                match keyword {
+                    "REM" => {
+                        let len = rest.len();
+                        push(&mut out, Kind::Remark, len, &mut rest);
+                        break;
+                    },
+                    "DATA" => {
+                        let mut quoted = false;
+                        let len = rest.char_indices()
+                            .find(|&(_, c)| {
+                                if c == '"' {
+                                    quoted = !quoted;
+                                }
+                                c == ':' && !quoted
+                            })
+                            .map_or(rest.len(), |(n, _)| n);
+                        push(&mut out, Kind::Data, len, &mut rest);
+                    },
+                    _ => {},
+                }
+                continue;
+            }
+            // 変数名は英数字が続く間 (途中に命令があればそこまで)
+            let mut len = c.len_utf8();
+            while let Some(next) = rest[len..].chars().next() {
+                if !next.is_ascii_alphanumeric() {
+                    break;
+                }
+                if next.is_ascii_alphabetic() && tokenize::match_keyword(&rest.as_bytes()[len..]).is_some() {
+                    break;
+                }
+                len += 1;
+            }
+            // 型宣言文字
+            if matches!(rest[len..].chars().next(), Some('$') | Some('%') | Some('!') | Some('#')) {
+                len += 1;
+            }
+            push(&mut out, Kind::Name, len, &mut rest);
+            line_number = false;
+            erl = false;
+            continue;
+        }
+        if c.is_ascii_digit() && (line_number || erl) {
+            let len = span(rest, |c| c.is_ascii_digit());
+            push(&mut out, Kind::LineRef, len, &mut rest);
+            erl = false;
+            continue;
+        }
+        if c.is_ascii_digit() || c == '.' || c == '&' {
+            let len = number_len(rest);
+            if len > 0 {
+                push(&mut out, Kind::Number, len, &mut rest);
+                line_number = false;
+                erl = false;
+                continue;
+            }
+        }
+        if c == ' ' {
+            let len = span(rest, |c| c == ' ');
+            push(&mut out, Kind::Space, len, &mut rest);
+            continue;
+        }
+        if "+-*/^\\=<>".contains(c) {
+            push(&mut out, Kind::Operator, 1, &mut rest);
+            // LIST 10-50 の範囲
+            if c != '-' {
+                line_number = false;
+            }
+            // ERL=100 / ERL<>100
+            if !"=<>".contains(c) {
+                erl = false;
+            }
+            continue;
+        }
+        if c != ',' {
+            line_number = false;
+        }
+        erl = false;
+        push(&mut out, Kind::Punct, c.len_utf8(), &mut rest);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(line: &str) -> Vec<(Kind, &str)> {
+        lex(line).into_iter().map(|l| (l.kind, l.text)).collect()
+    }
+
+    #[test]
+    fn test_lex() {
+        assert_eq!(kinds("ON X GOTO 10,20:PRINT\"A\""), [
+            (Kind::Keyword, "ON"), (Kind::Space, " "), (Kind::Name, "X"), (Kind::Space, " "),
+            (Kind::Keyword, "GOTO"), (Kind::Space, " "), (Kind::LineRef, "10"), (Kind::Punct, ","),
+            (Kind::LineRef, "20"), (Kind::Punct, ":"), (Kind::Keyword, "PRINT"), (Kind::Str, "\"A\""),
+        ]);
+        assert_eq!(kinds("IF ERL=100 THEN RESUME 20 ELSE 30"), [
+            (Kind::Keyword, "IF"), (Kind::Space, " "), (Kind::Keyword, "ERL"), (Kind::Operator, "="),
+            (Kind::LineRef, "100"), (Kind::Space, " "), (Kind::Keyword, "THEN"), (Kind::Space, " "),
+            (Kind::Keyword, "RESUME"), (Kind::Space, " "), (Kind::LineRef, "20"), (Kind::Space, " "),
+            (Kind::Keyword, "ELSE"), (Kind::Space, " "), (Kind::LineRef, "30"),
+        ]);
+        assert_eq!(kinds("a1$=left$(b,2)'x"), [
+            (Kind::Name, "a1$"), (Kind::Operator, "="), (Kind::Function, "left$"), (Kind::Punct, "("),
+            (Kind::Name, "b"), (Kind::Punct, ","), (Kind::Number, "2"), (Kind::Punct, ")"),
+            (Kind::Keyword, "'"), (Kind::Remark, "x"),
+        ]);
+        assert_eq!(kinds("DATA 1,\"a:b\":X=&HFF+1.5E3"), [
+            (Kind::Keyword, "DATA"), (Kind::Data, " 1,\"a:b\""), (Kind::Punct, ":"), (Kind::Name, "X"),
+            (Kind::Operator, "="), (Kind::Number, "&HFF"), (Kind::Operator, "+"), (Kind::Number, "1.5E3"),
+        ]);
+        // 変数名の途中の命令 (MSX では SCORE は SC OR E になる)
+        assert_eq!(kinds("SCORE")[1], (Kind::Keyword, "OR"));
+        assert_eq!(lex("LIST 10-50").iter().filter(|l| l.kind == Kind::LineRef).count(), 2);
+    }
+}
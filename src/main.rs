@@ -10,23 +10,44 @@
 //
 mod msxcode;
 mod connection;
+mod error;
+mod screen;
+mod rawmode;
+mod transfer;
+mod diff;
+mod tokenize;
+mod lexer;
+mod renum;
+mod preprocess;
+mod check;
+mod highlight;
+mod helper;
+mod complete;
+mod config;
+mod sessionlog;
+mod cast;
+mod script;
+mod batch;
+#[cfg(test)]
+mod sim;
 
-use std::net::{Shutdown, TcpStream};
 use std::thread;
 
 use rustyline::config::Configurer;
-//use std::sync::Arc;
-use rustyline::{DefaultEditor, EditMode, ExternalPrinter, Result, error::ReadlineError};
+use rustyline::{EditMode, ExternalPrinter, Result, error::ReadlineError};
 use std::collections::{BTreeMap, HashMap};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::fs::File;
 use std::io::{BufRead, Write, BufReader,BufWriter};
 use std::path::PathBuf;
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 //use serialport::{ SerialPort, SerialPortType, available_ports};
 use serial2::{SerialPort};
-//use crate::connection::{TcpConnection, SerialConnection};
+use crate::connection::{ConnectionType, ConnectionError, SerialParam};
+use crate::error::MsxError;
+use crate::helper::{MsxEditor, MsxHelper};
 
 const C_CR: char = '\u{000d}';
 const C_LF: char = '\u{000a}';
@@ -37,6 +58,19 @@ const U_LF:u8 = 0x0a;
 const U_CR:u8 = 0x0d;
 const U_PAUSE:u8 = 0x7b;
 
+// ダンプ表示用 LF の後で改行する
+fn dump_hex_lines(uv: &[u8]) -> String
+{
+    let mut cv = String::new();
+    for &u in uv {
+        cv.push_str(&format!("{:02X} ", u));
+        if u == U_LF {
+            cv.push('\n');
+        }
+    }
+    cv
+}
+
 fn dump_hex(uv: Vec<u8>) -> String
 {
     let mut cv:String = "".to_string();
@@ -53,40 +87,71 @@ fn test_dump_hex() {
     let s = dump_hex(uv);
     println!("{}",s);
     assert!(s == "41 51 61 71 80 81 8A B3 C4 55 ");
+    assert_eq!(dump_hex_lines(&[0x4f, 0x6b, 0x0d, 0x0a, 0x41]), "4F 6B 0D 0A \n41 ");
 }
 
 
 #[test]
 fn test_hex () {
     let s = "#HEX 40 41 42 43 44";
-    let v = hex2u8(s);
+    let v = hex2u8(s).unwrap();
     println!("{:?}", v);
+    assert_eq!(v, vec![0x40, 0x41, 0x42, 0x43, 0x44]);
+    assert!(hex2u8("#hex 40 4G").is_err());
 }
 
-fn hex2u8(hex: &str) -> Vec<u8> {
+fn hex2u8(hex: &str) -> error::Result<Vec<u8>> {
     let mut hex_vec: Vec<u8> = Vec::new();
-    let tokens: Vec<&str> = hex.split(' ').collect();
-    for token in &tokens[1..] {
-        if let Ok(val) = u8::from_str_radix(token, 16) {
-            hex_vec.push(val)   
+    for token in hex.split_whitespace().skip(1) {
+        match u8::from_str_radix(token, 16) {
+            Ok(val) => hex_vec.push(val),
+            Err(_) => return Err(MsxError::Command(format!("Bad hex: {}", token))),
         }
     }
-    hex_vec
+    Ok(hex_vec)
+}
+
+//
+// コマンドの引数からファイルのパスを取り出す
+// 空白を含むパスはダブルクォートで囲う
+//
+fn path_arg(command_line: &str) -> error::Result<PathBuf> {
+    let mut parts = command_line.trim().splitn(2, ' ');
+    let command = parts.next().unwrap_or("");
+    let path_str = parts.next().unwrap_or("").trim().trim_matches('\"');
+    if path_str.is_empty() {
+        return Err(MsxError::Command(format!("Usage: {} <file>", command)));
+    }
+    Ok(PathBuf::from(path_str))
 }
 
 //
 // 指定されたファイルをロードしてvec<String>を返す
 //
-fn load(command_line: &str) -> Result<Vec<String>> {
-    let tokens: Vec<&str> = command_line.split(' ').collect();
-    let path_str = tokens[1];
+fn load(command_line: &str) -> error::Result<Vec<String>> {
     // ファイルのパス
-    let path = PathBuf::from(path_str.trim_matches('\"'));
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);       
+    let path = path_arg(command_line)?;
+    load_file(&path)
+}
+
+fn load_file(path: &std::path::Path) -> error::Result<Vec<String>> {
+    let file_err = |e| MsxError::File(path.display().to_string(), e);
+    let data = std::fs::read(path).map_err(file_err)?;
+    // 中間言語形式 (0xFF で始まる) ならテキストに戻す
+    if tokenize::is_tokenized(&data) {
+        let lines = tokenize::decode_program(&data)?;
+        return Ok(lines.into_iter()
+            .map(|(num, text)| format!("{} {}", num, msxcode::msx_ascii_to_string(text)))
+            .collect());
+    }
+    let reader = BufReader::new(&data[..]);
     let mut lines = Vec::new();
     for line in reader.lines() {
-        lines.push(line?);
+        lines.push(line.map_err(file_err)?);
+    }
+    // 行番号なしのソースなら行番号を振る
+    if preprocess::is_source(&lines) {
+        return preprocess::load_source(path);
     }
     Ok(lines)
 }
@@ -95,31 +160,241 @@ fn load(command_line: &str) -> Result<Vec<String>> {
 // コマンドラインオプションの設定
 //
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Host_IP or Serial_Port
+    #[command(subcommand)]
+    command: Option<SubCommand>,
+
+    /// Host_IP or Serial_Port, or @profile in the config file
     target: Option<String>,
 
-    /// history file name
-    #[arg(short, long, value_name = "history file", default_value = "history.txt")]
-    file: String,
+    /// history file name [default: history.txt]
+    #[arg(short, long, value_name = "history file")]
+    file: Option<String>,
 
-    #[arg(short, long, value_name= "emacs or vi", default_value = "emacs")]
+    /// [default: emacs]
+    #[arg(short, long, value_name= "emacs or vi")]
     editor: Option<String>,
 
-    /// Use Serial Port
-    #[arg(short, long)]
+    // 以前のオプション (シリアルポートは接続先から判別するので何もしない)
+    #[arg(short, long, hide = true)]
     serial: bool,
 
     /// Display Serial Port List
     #[arg(short, long)]
     port_list: bool,
+
+    /// Serial baud rate [default: 115200]
+    #[arg(short, long, value_name = "baud rate")]
+    baud: Option<u32>,
+
+    /// Serial data bits (5, 6, 7, 8) [default: 8]
+    #[arg(long, value_name = "bits")]
+    data_bits: Option<u8>,
+
+    /// Serial parity (none, odd, even) [default: none]
+    #[arg(long, value_name = "parity")]
+    parity: Option<String>,
+
+    /// Serial stop bits (1, 2) [default: 1]
+    #[arg(long, value_name = "bits")]
+    stop_bits: Option<u8>,
+
+    /// Serial flow control (none, xonxoff, rtscts) [default: none]
+    #[arg(long, value_name = "flow control")]
+    flow: Option<String>,
+
+    /// Key chord to leave #raw mode (e.g. ctrl-], alt-q, f12) [default: ctrl-]]
+    #[arg(long, value_name = "key")]
+    raw_escape: Option<String>,
+
+    /// Start in Kanji mode (on, off)
+    #[arg(long, value_name = "on|off", value_parser = parse_on_off)]
+    kanji: Option<bool>,
+
+    /// Start in dump mode (on, off)
+    #[arg(long, value_name = "on|off", value_parser = parse_on_off)]
+    dump: Option<bool>,
+
+    /// Paced #load (on, off) [default: off]
+    #[arg(long, value_name = "on|off", value_parser = parse_on_off)]
+    paced: Option<bool>,
+
+    /// Color scheme (default, light, none)
+    #[arg(long, value_name = "scheme")]
+    color: Option<String>,
+
+    /// Config file [default: ~/.config/msxterm/config.toml]
+    #[arg(long, value_name = "file")]
+    config: Option<PathBuf>,
+
+    /// Record the session to a log file
+    #[arg(long, value_name = "file")]
+    log: Option<PathBuf>,
+
+    /// Put timestamps on each line of the log
+    #[arg(long, requires = "log")]
+    time: bool,
+
+    /// Also record the TX/RX bytes to <log file>.raw
+    #[arg(long, requires = "log")]
+    raw: bool,
+
+    /// Run a command script and quit (exit status 1 on failure)
+    #[arg(long, value_name = "file.msxs")]
+    script: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum SubCommand {
+    /// Play back a #record cast or a --raw log (RX) without a device
+    Replay {
+        /// .cast file or <log file>.raw
+        file: PathBuf,
+
+        /// Playback speed
+        #[arg(long, default_value = "1.0", value_parser = parse_speed)]
+        speed: f64,
+
+        /// Limit pauses to this many seconds
+        #[arg(long, value_name = "sec", value_parser = parse_seconds)]
+        idle_limit: Option<f64>,
+
+        /// Decode a raw log in Kanji mode
+        #[arg(long)]
+        kanji: bool,
+    },
+    /// Send a program, RUN it and print the output (exit 1 on "error in", 2 on timeout)
+    Run {
+        /// Host_IP or Serial_Port, or @profile in the config file
+        target: String,
+
+        /// BASIC program (same as #load)
+        file: PathBuf,
+
+        /// Stop at the first line matching this regex
+        #[arg(long, value_name = "regex", default_value = "^Ok$")]
+        until: String,
+
+        /// Give up after this many seconds
+        #[arg(long, value_name = "sec", default_value = "30")]
+        timeout: f64,
+
+        /// Kanji mode (on, off)
+        #[arg(long, value_name = "on|off", value_parser = parse_on_off)]
+        kanji: Option<bool>,
+
+        /// Config file [default: ~/.config/msxterm/config.toml]
+        #[arg(long, value_name = "file")]
+        config: Option<PathBuf>,
+    },
+}
+
+// 0 より大きい倍率
+fn parse_speed(s: &str) -> std::result::Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v.is_finite() && v > 0.0 => Ok(v),
+        _ => Err(format!("expected a positive number: {}", s)),
+    }
+}
+
+// 0 以上の秒数
+fn parse_seconds(s: &str) -> std::result::Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if v.is_finite() && v >= 0.0 => Ok(v),
+        _ => Err(format!("expected seconds (0 or more): {}", s)),
+    }
+}
+
+fn parse_on_off(s: &str) -> std::result::Result<bool, String> {
+    match s {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("expected on or off: {}", s)),
+    }
+}
+
+impl Args {
+    // 接続先 (run の場合はその引数)
+    fn target(&self) -> Option<&str> {
+        match self.command {
+            Some(SubCommand::Run { ref target, .. }) => Some(target),
+            _ => self.target.as_deref(),
+        }
+    }
+
+    // コマンドラインで指定した項目 (設定ファイルのプロファイルより優先)
+    fn profile(&self) -> config::Profile {
+        let kanji = match self.command {
+            Some(SubCommand::Run { kanji, .. }) => kanji,
+            _ => self.kanji,
+        };
+        config::Profile {
+            target: self.target().filter(|t| !t.starts_with('@')).map(|t| t.to_string()),
+            history: self.file.clone(),
+            editor: self.editor.clone(),
+            baud: self.baud,
+            data_bits: self.data_bits,
+            parity: self.parity.clone(),
+            stop_bits: self.stop_bits,
+            flow: self.flow.clone(),
+            raw_escape: self.raw_escape.clone(),
+            kanji,
+            dump: self.dump,
+            paced: self.paced,
+            color: self.color.clone(),
+        }
+    }
+
+    // 設定ファイルを読んでプロファイルを選び、コマンドラインの指定を重ねる
+    fn settings(&self) -> error::Result<config::Profile> {
+        let path = match self.command {
+            Some(SubCommand::Run { ref config, .. }) => config.clone(),
+            _ => self.config.clone(),
+        };
+        let config = match (path, config::default_path()) {
+            (Some(ref path), _) => config::Config::load(path)?,
+            (None, Some(ref path)) => config::Config::load_default(path)?,
+            (None, None) => config::Config::default(),
+        };
+        let name = self.target().and_then(|t| t.strip_prefix('@'));
+        Ok(config.select(name)?.overlay(self.profile()))
+    }
+}
+
+// シリアルポートのパラメータを組み立てる
+fn serial_param(settings: &config::Profile) -> std::result::Result<SerialParam, String> {
+    let mut sp = SerialParam::default();
+    if let Some(baud) = settings.baud {
+        sp.set("baud", &baud.to_string())?;
+    }
+    if let Some(bits) = settings.data_bits {
+        sp.set("data", &bits.to_string())?;
+    }
+    if let Some(ref parity) = settings.parity {
+        sp.set("parity", parity)?;
+    }
+    if let Some(bits) = settings.stop_bits {
+        sp.set("stop", &bits.to_string())?;
+    }
+    if let Some(ref flow) = settings.flow {
+        sp.set("flow", flow)?;
+    }
+    Ok(sp)
 }
 
 struct Msxterm {
     dump_mode: bool,
     lower_mode: bool,
     kanji_mode: bool,
+    screen_emu: bool,
+    screen_width: usize,
+    raw_escape: rawmode::KeyChord,
+    paced: bool,
+    color: highlight::Scheme,
+    // #record の記録先
+    recording: Option<PathBuf>,
+    serial_param: SerialParam,
     prog_buff:BTreeMap<u16, String>,
     t_com: HashMap<String, String>,
 }
@@ -130,12 +405,52 @@ impl Msxterm {
             dump_mode: false, 
             lower_mode: false,
             kanji_mode: false,
+            screen_emu: false,
+            screen_width: 80,
+            raw_escape: rawmode::KeyChord::default(),
+            paced: false,
+            color: highlight::Scheme::default(),
+            recording: None,
+            serial_param: SerialParam::default(),
             prog_buff: BTreeMap::new(), 
             t_com: HashMap::new(),
         }
     }
     fn init(&mut self) {
-        self.t_com.insert("#list".to_string(), "list Program".to_string());
+        let commands = [
+            ("#quit", "Quit msxterm"),
+            ("#hex", "Send bytes in hex"),
+            ("#dump_on", "Dump received bytes"),
+            ("#dump_off", "Stop dumping"),
+            ("#lowsend_on", "Send in lowercase"),
+            ("#lowsend_off", "Send as typed"),
+            ("#kanji_on", "Shift-JIS mode"),
+            ("#kanji_off", "MSX character mode"),
+            ("#screen_emu", "Screen emulation on|off"),
+            ("#mirror", "Mirror MSX screen on|off"),
+            ("#raw", "Raw key mode"),
+            ("#paced", "Paced load on|off"),
+            ("#serial", "Serial parameters"),
+            ("#emacs", "Emacs key bindings"),
+            ("#vi", "Vi key bindings"),
+            ("#clear_history", "Clear history"),
+            ("#new", "Clear Program"),
+            ("#load", "load Program"),
+            ("#save", "save Program"),
+            ("#list", "list Program"),
+            ("#renum", "renumber Program"),
+            ("#check", "check Program"),
+            ("#log", "Session log <file>|off"),
+            ("#record", "Record to <file.cast>|off"),
+            ("#run_script", "Run <file.msxs>"),
+            ("#reload_from", "Program from MSX"),
+            ("#reload_to", "Program to MSX"),
+            ("#diff", "Compare with MSX"),
+            ("#sync", "Send differences to MSX"),
+        ];
+        for (command, description) in commands {
+            self.t_com.insert(command.to_string(), description.to_string());
+        }
     }
 
     pub fn parse_basic(&mut self, line:&str) {
@@ -151,6 +466,29 @@ impl Msxterm {
         }
     }
 
+    // 送信用に MSX 側の文字コードへ変換する
+    pub fn encode(&self, text: &str) -> error::Result<Vec<u8>> {
+        let mut tmp = text.to_string();
+        if self.lower_mode {
+            tmp = lower_program(&tmp);
+        }
+        self.encode_raw(&tmp)
+    }
+
+    // raw モードの一文字分 (小文字送信モードは使わない)
+    pub fn encode_char(&self, c: char) -> error::Result<Vec<u8>> {
+        self.encode_raw(&c.to_string())
+    }
+
+    fn encode_raw(&self, text: &str) -> error::Result<Vec<u8>> {
+        if self.kanji_mode {
+            msxcode::utf8_to_msx_kanji(text)
+                .map_err(|bad| MsxError::Codec(format!("Cannot convert to Shift-JIS: {}", bad)))
+        } else {
+            Ok(msxcode::utf8_msx_jp_code(text))
+        }
+    }
+
     pub fn print_basic(&mut self, start:u16, end:u16) -> Vec<String> {
         // println!("list {} {}", start, end);
         let mut history = Vec::new(); 
@@ -160,7 +498,7 @@ impl Msxterm {
             //for (num, inst) in &self.prog_buff {
             for (num ,inst) in iter {
                 let padding = " ".repeat(maxlen - num.to_string().len());
-                println!("{}\x1b[36m{}\x1b[0m {}",padding, num, inst);
+                println!("{}{}", padding, highlight::highlight_line(&num.to_string(), inst, &self.color));
                 history.push(std::format!("{} {}", num, inst ));
             }
         }
@@ -171,25 +509,34 @@ impl Msxterm {
         self.prog_buff.clear();
     }
 
-    pub fn save_program(&self, command_line:&str) {
-        let tokens: Vec<&str> = command_line.split(' ').collect();
-        let path_str = tokens[1];
+    pub fn save_program(&self, command_line:&str) -> error::Result<()> {
         // ファイルのパス
-        let path = PathBuf::from(path_str.trim_matches('\"'));
+        let path = path_arg(command_line)?;
+        let file_err = |e| MsxError::File(path.display().to_string(), e);
         // ファイルを作成する
-        let file = File::create(path).expect("Failed to create file");
+        let file = File::create(&path).map_err(file_err)?;
         // ファイルに書き込むためのBufWriterを作成する
         let mut writer = BufWriter::new(file);
 
         // BTreeMapを文字列に変換してファイルに書き込む
         for (line_number, program) in self.prog_buff.iter() {
             let line = format!("{} {}\n", line_number, program);
-            writer.write_all(line.as_bytes()).expect("Failed to write to file");
+            writer.write_all(line.as_bytes()).map_err(file_err)?;
         }
         // ファイルをクローズする
-        writer.flush().expect("Failed to flush buffer");
+        writer.flush().map_err(file_err)
     }    
 
+    // MSX-BASIC の中間言語形式で保存する
+    pub fn save_tokenized(&self, command_line:&str) -> error::Result<()> {
+        let path = path_arg(command_line)?;
+        let program: BTreeMap<u16, Vec<u8>> = self.prog_buff.iter()
+            .map(|(num, line)| (*num, msxcode::utf8_msx_jp_code(line)))
+            .collect();
+        let data = tokenize::encode_program(&program)?;
+        std::fs::write(&path, data).map_err(|e| MsxError::File(path.display().to_string(), e))
+    }
+
 
 }
 
@@ -213,6 +560,24 @@ enum Command {
     DumpModeOff,
     KanjiModeOn,
     KanjiModeOff,
+    ScreenEmuOn,
+    ScreenEmuOff,
+    MirrorOn(usize),
+    MirrorOff,
+    ScreenWidth(usize),
+    RawModeOn,
+    RawModeOff,
+    // 受信した内容を表示しない
+    Quiet(bool),
+    // 受信した行を送り返してもらう (受け付けたら二つ目に通知)
+    Watch(Sender<String>, Sender<()>),
+    Unwatch,
+    // 端末への出力を asciicast で記録する
+    RecordOn(cast::Recorder),
+    RecordOff,
+    SerialParam(SerialParam),
+    Reconnect,
+    Quit,
 }
 
 #[test]
@@ -256,6 +621,46 @@ fn test_msxterm () {
     */
 }
 
+#[test]
+fn test_tokenized_file() {
+    // 中間言語形式で保存して読み戻す
+    let mut mt = Msxterm::new();
+    for s in load("#load ./src/test.bas").unwrap() {
+        mt.parse_basic(&s);
+    }
+    let path = std::env::temp_dir().join(format!("msxterm_test_{}.bas", std::process::id()));
+    mt.save_tokenized(&format!("#save {}", path.display())).unwrap();
+    let data = std::fs::read(&path).unwrap();
+    assert_eq!(data[0], tokenize::BAS_HEADER);
+
+    let lines = load(&format!("#load {}", path.display())).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let expected: Vec<String> = mt.prog_buff.iter().map(|(n, l)| format!("{} {}", n, l)).collect();
+    assert_eq!(lines, expected);
+}
+
+#[test]
+fn test_sim_load() {
+    // シミュレータに #load して LIST の結果と比較する
+    // capture_list は応答がなければタイムアウトする
+    let link = transfer::tests::sim_link();
+    let mut mt = Msxterm::new();
+    let mut rl = MsxEditor::new().unwrap();
+    for paced in [false, true] {
+        mt.paced = paced;
+        mt.clear_basic();
+        assert!(exec_line("NEW", &mut mt, &mut rl, &link).unwrap());
+        assert!(exec_line("#load ./src/test.bas", &mut mt, &mut rl, &link).unwrap());
+        assert!(exec_line("10 PRINT \"こんにちは\"", &mut mt, &mut rl, &link).unwrap());
+        mt.parse_basic("10 PRINT \"こんにちは\"");
+
+        let listing = transfer::capture_list(&mt, &link).unwrap();
+        let expected: Vec<String> = mt.prog_buff.iter().map(|(n, l)| format!("{} {}", n, l)).collect();
+        assert_eq!(listing, expected);
+        assert_eq!(listing[0], "10 PRINT \"こんにちは\"");
+    }
+}
+
 fn lower_program(input:&str) -> String {
     let mut output = String::new();
     let mut is_quoted = false;
@@ -297,17 +702,894 @@ fn test_lower_program() {
     println!("{}", result);
 }
 
-fn serial_port_list() {
+fn serial_port_list() -> error::Result<()> {
     // シリアルポートの情報を取得する
-    let ports = SerialPort::available_ports().expect("Failed to get serial port list");
+    let ports = SerialPort::available_ports().map_err(ConnectionError::Io)?;
 
     // USB接続されたシリアルポートを検索する
     for port in ports {
-        let str = port.into_os_string().into_string().unwrap();
-        println!("USB Serial Port found: {}", str);
+        println!("USB Serial Port found: {}", port.display());
     }
+    Ok(())
 }
 
+// 再接続の待ち時間 (秒) の上限
+const RECONNECT_MAX_WAIT: u64 = 30;
+
+//
+// 受信スレッド
+// 切断を検出したら送信側を切断状態にして、バックオフしながら再接続する
+//
+fn receive_loop<P: ExternalPrinter>(
+    mut conn_read: ConnectionType,
+    conn: Arc<Mutex<ConnectionType>>,
+    log: SharedLog,
+    target: String,
+    mut serial_param: SerialParam,
+    rx: Receiver<Command>,
+    mut printer: P,
+) {
+    let mut dump_mode = false;
+    let mut decoder = msxcode::MsxDecoder::new(false);
+    let mut screen_emu: Option<screen::Vt52Translator> = None;
+    let mut mirror: Option<screen::MsxScreen> = None;
+    let mut raw_mode = false;
+    let mut quiet = false;
+    let mut watcher: Option<Sender<String>> = None;
+    let mut watch_buf = String::new();
+    let mut recorder: Option<cast::Recorder> = None;
+    // 表示待ちの文字列
+    let mut pending = String::new();
+    // pending を改行のない行として表示済み
+    let mut shown = false;
+    let mut read_buff = [0x00_u8; 256];
+    let mut connected = true;
+    let mut wait_sec = 1;
+    let mut next_try = Instant::now();
+    loop {
+        while let Ok(command) = rx.try_recv() {
+            match command {
+                Command::DumpModeOn => dump_mode = true,
+                Command::DumpModeOff => dump_mode = false,
+                Command::KanjiModeOn => decoder.set_kanji_mode(true),
+                Command::KanjiModeOff => decoder.set_kanji_mode(false),
+                Command::ScreenEmuOn => screen_emu = Some(screen::Vt52Translator::new()),
+                Command::ScreenEmuOff => screen_emu = None,
+                Command::MirrorOn(width) => {
+                    let sc = screen::MsxScreen::new(width);
+                    write_tty(&sc.enter_mirror());
+                    mirror = Some(sc);
+                },
+                Command::MirrorOff => {
+                    if mirror.take().is_some() {
+                        write_tty(&screen::MsxScreen::leave_mirror());
+                    }
+                },
+                Command::ScreenWidth(width) => {
+                    if let Some(ref mut sc) = mirror {
+                        sc.set_width(width);
+                        write_tty(&sc.enter_mirror());
+                    }
+                },
+                Command::RawModeOn => raw_mode = true,
+                Command::RawModeOff => raw_mode = false,
+                Command::Watch(tx, ack) => {
+                    watcher = Some(tx);
+                    watch_buf.clear();
+                    let _ = ack.send(());
+                },
+                Command::Unwatch => watcher = None,
+                Command::RecordOn(r) => recorder = Some(r),
+                Command::RecordOff => recorder = None,
+                Command::Quiet(q) => quiet = q,
+                Command::SerialParam(sp) => serial_param = sp,
+                Command::Reconnect => {
+                    if connected {
+                        connected = false;
+                        next_try = Instant::now();
+                    }
+                },
+                Command::Quit => return,
+            }
+        }
+
+        if !connected {
+            if Instant::now() < next_try {
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            external_print(&mut printer, format!("Reconnecting... {}", target));
+            log_note(&log, &format!("Reconnecting... {}", target));
+            match connection::create_connection(&target, &serial_param) {
+                Err(e) => {
+                    external_print(&mut printer, format!("{} (retry in {} sec)", e, wait_sec));
+                    next_try = Instant::now() + Duration::from_secs(wait_sec);
+                    wait_sec = (wait_sec * 2).min(RECONNECT_MAX_WAIT);
+                },
+                Ok(new_conn) => {
+                    match new_conn.try_clone() {
+                        Ok(c) => {
+                            conn_read = c;
+                            *conn.lock().unwrap() = new_conn;
+                            connected = true;
+                            wait_sec = 1;
+                            decoder.set_kanji_mode(decoder.kanji_mode());
+                            pending.clear();
+                            shown = false;
+                            log_note(&log, "reconnected.");
+                            // モード状態は切断前のものを引き継ぐ
+                            external_print(&mut printer, format!("reconnected. (kanji mode {}, dump mode {})",
+                                if decoder.kanji_mode() { "On" } else { "Off" },
+                                if dump_mode { "On" } else { "Off" }));
+                        },
+                        Err(e) => {
+                            external_print(&mut printer, e.to_string());
+                            next_try = Instant::now() + Duration::from_secs(wait_sec);
+                        }
+                    }
+                }
+            }
+            continue;
+        }
+
+        match conn_read.read(&mut read_buff) {
+            Ok(0) => {
+                external_print(&mut printer, "Disconnect".to_string());
+                log_note(&log, "Disconnect");
+                disconnect(&conn);
+                connected = false;
+                next_try = Instant::now() + Duration::from_secs(wait_sec);
+                continue;
+            },
+            Ok(size) => {
+                let data = &read_buff[..size];
+                let mut session_log = log.lock().unwrap();
+                if let Some(ref mut l) = *session_log {
+                    l.bytes(sessionlog::Direction::Rx, data);
+                }
+                if dump_mode {
+                    let hex = dump_hex_lines(data);
+                    if let Some(ref mut r) = recorder {
+                        r.output(&hex);
+                    }
+                    pending.push_str(&hex);
+                } else {
+                    let text = decoder.decode(data);
+                    if let Some(ref mut l) = *session_log {
+                        l.output(&text);
+                    }
+                    if let Some(ref tx) = watcher {
+                        watch_buf.push_str(&text);
+                        if !forward_lines(tx, &mut watch_buf) {
+                            watcher = None;
+                        }
+                    }
+                    if quiet {
+                        // 表示しない
+                    } else if let Some(ref mut sc) = mirror {
+                        // ミラー表示中は画面モデルに書き込んで再描画する
+                        sc.feed(&text);
+                        let screen = sc.render();
+                        if let Some(ref mut r) = recorder {
+                            r.output(&screen);
+                        }
+                        write_tty(&screen);
+                    } else {
+                        let shown = match screen_emu {
+                            Some(ref mut tr) => tr.translate(&text),
+                            None => text,
+                        };
+                        if let Some(ref mut r) = recorder {
+                            r.output(&shown);
+                        }
+                        pending.push_str(&shown);
+                    }
+                }
+            },
+            Err(e) if connection::is_timeout(&e) => {
+                if let Some(ref mut l) = *log.lock().unwrap() {
+                    l.flush();
+                }
+                if let Some(ref mut r) = recorder {
+                    r.flush();
+                }
+                // 受信が途切れたら改行のない出力 (プロンプトなど) も表示する
+                // 行の続きが届いたら表示し直すので pending には残しておく
+                if raw_mode && !pending.is_empty() {
+                    write_tty(&raw_newline(&std::mem::take(&mut pending)));
+                } else if !pending.is_empty() && !shown {
+                    external_print(&mut printer, pending.clone());
+                    shown = true;
+                }
+                continue;
+            },
+            Err(e) => {
+                external_print(&mut printer, e.to_string());
+                log_note(&log, &e.to_string());
+                disconnect(&conn);
+                connected = false;
+                next_try = Instant::now() + Duration::from_secs(wait_sec);
+                continue;
+            }
+        }
+        // raw モード中は行の途中でもすぐに表示する
+        if raw_mode {
+            write_tty(&raw_newline(&std::mem::take(&mut pending)));
+            shown = false;
+            continue;
+        }
+        if shown {
+            // 表示済みの行は続きと合わせて書き直す
+            match pending.rfind('\n') {
+                Some(pos) => {
+                    let lines: String = pending.drain(..=pos).collect();
+                    external_print(&mut printer, format!("{}{}", REDRAW, lines));
+                    shown = false;
+                },
+                None => external_print(&mut printer, format!("{}{}", REDRAW, pending)),
+            }
+        } else if let Some(pos) = pending.rfind('\n') {
+            // 改行までは受信し次第表示
+            let lines: String = pending.drain(..=pos).collect();
+            external_print(&mut printer, lines);
+        }
+    }
+}
+
+// 表示済みの改行のない行を消して書き直す
+// rustyline は改行のない表示の後ろに改行を足すので、その行は入力行の一つ上にある
+const REDRAW: &str = "\x1b[1A\r\x1b[K";
+
+// 端末を使わない表示で書く文字列
+// 書き直しの場合は表示済みの部分を除いた続きだけにする
+// last は前回書いた改行のない行
+pub(crate) fn plain_text(msg: &str, last: &mut String) -> String {
+    let (text, redraw) = match msg.strip_prefix(REDRAW) {
+        Some(m) => (m, true),
+        None => (msg, false),
+    };
+    let out = match text.strip_prefix(last.as_str()) {
+        Some(rest) if redraw => rest,
+        _ => text,
+    };
+    *last = if text.ends_with('\n') { String::new() } else { text.to_string() };
+    out.to_string()
+}
+
+// 受信スレッドの表示を集めるプリンタ
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct CapturePrinter(Arc<Mutex<Vec<String>>>);
+
+#[cfg(test)]
+impl ExternalPrinter for CapturePrinter {
+    fn print(&mut self, msg: String) -> Result<()> {
+        self.0.lock().unwrap().push(msg);
+        Ok(())
+    }
+}
+
+// addr に接続して受信スレッドを動かす
+#[cfg(test)]
+fn spawn_receiver(addr: &str, printer: CapturePrinter) -> Link {
+    let conn = connection::create_connection(addr, &SerialParam::default()).unwrap();
+    let conn_read = conn.try_clone().unwrap();
+    let (tx, rx) = channel();
+    let link = Link { conn: Arc::new(Mutex::new(conn)), tx, log: Arc::new(Mutex::new(None)) };
+    let conn_shared = Arc::clone(&link.conn);
+    let log_shared = Arc::clone(&link.log);
+    let target = addr.to_string();
+    thread::spawn(move || {
+        receive_loop(conn_read, conn_shared, log_shared, target, SerialParam::default(), rx, printer);
+    });
+    link
+}
+
+#[test]
+fn test_receive_partial_line() {
+    // 受信が途切れても改行のない行は確定させず、続きが届いたら書き直す
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let printer = CapturePrinter::default();
+    let link = spawn_receiver(&addr, printer.clone());
+    let (mut server, _) = listener.accept().unwrap();
+    for data in ["Name? ", "ABC", "\r\nOk\r\n"] {
+        server.write_all(data.as_bytes()).unwrap();
+        thread::sleep(Duration::from_millis(300));
+    }
+    let _ = link.command(Command::Quit);
+    assert_eq!(*printer.0.lock().unwrap(), [
+        "Name? ".to_string(),
+        format!("{}Name? ABC", REDRAW),
+        format!("{}Name? ABC\r\nOk\r\n", REDRAW),
+    ]);
+
+    // 端末を使わない表示では続きだけを書く
+    let mut last = String::new();
+    let written: Vec<String> = printer.0.lock().unwrap().iter().map(|m| plain_text(m, &mut last)).collect();
+    assert_eq!(written, ["Name? ", "ABC", "\r\nOk\r\n"]);
+}
+
+// 受信スレッドの表示に text を含むものが出るまで待つ
+#[cfg(test)]
+fn wait_print(printer: &CapturePrinter, text: &str) -> bool {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if printer.0.lock().unwrap().iter().any(|m| m.contains(text)) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+#[test]
+fn test_reconnect() {
+    // 相手から切断されたら送信は NotConnected になり、再接続してもモードは引き継ぐ
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let printer = CapturePrinter::default();
+    let link = spawn_receiver(&addr, printer.clone());
+    link.command(Command::KanjiModeOn).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    drop(server);
+    assert!(wait_print(&printer, "Disconnect"));
+    assert!(matches!(link.send(b"\r"), Err(MsxError::Connection(ConnectionError::NotConnected(_)))));
+
+    let (mut server, _) = listener.accept().unwrap();
+    assert!(wait_print(&printer, "reconnected. (kanji mode On"));
+    let mut data = msxcode::utf8_to_msx_kanji("漢字").unwrap();
+    data.extend_from_slice(b"\r\n");
+    server.write_all(&data).unwrap();
+    assert!(wait_print(&printer, "漢字\r\n"));
+
+    link.send(b"NEW\r").unwrap();
+    let mut buf = [0_u8; 4];
+    std::io::Read::read_exact(&mut server, &mut buf).unwrap();
+    assert_eq!(&buf, b"NEW\r");
+    let _ = link.command(Command::Quit);
+}
+
+/// 送信側と受信スレッドで共有するセッションログ
+type SharedLog = Arc<Mutex<Option<sessionlog::SessionLog>>>;
+
+fn log_note(log: &SharedLog, message: &str) {
+    if let Some(ref mut l) = *log.lock().unwrap() {
+        l.note(message);
+    }
+}
+
+// 受信した文字列から完成した行を取り出して送る
+// 受け取り側がいなくなったら false を返す
+fn forward_lines(tx: &Sender<String>, buf: &mut String) -> bool {
+    while let Some(pos) = buf.find('\n') {
+        let line: String = buf.drain(..=pos).collect();
+        if tx.send(line.trim_end_matches(['\r', '\n']).to_string()).is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+// 受信スレッドからの表示
+// 表示できなかった場合は標準エラーに出す
+fn external_print<P: ExternalPrinter>(printer: &mut P, msg: String) {
+    if let Err(e) = printer.print(msg) {
+        eprintln!("External print failure: {}", e);
+    }
+}
+
+// 端末がない場合 (--script を cron から動かすなど) は標準出力にそのまま書く
+#[derive(Default)]
+struct StdoutPrinter {
+    last: String,
+}
+
+impl ExternalPrinter for StdoutPrinter {
+    fn print(&mut self, msg: String) -> Result<()> {
+        write_tty(&plain_text(&msg, &mut self.last));
+        Ok(())
+    }
+}
+
+// ターミナルへ直接出力する (ミラー表示用)
+fn write_tty(s: &str) {
+    let mut out = std::io::stdout().lock();
+    let _ = out.write_all(s.as_bytes());
+    let _ = out.flush();
+}
+
+// raw モードの端末は LF だけでは行頭に戻らない
+fn raw_newline(s: &str) -> String {
+    s.replace("\r\n", "\n").replace('\n', "\r\n")
+}
+
+// 送信側を切断状態にする
+fn disconnect(conn: &Arc<Mutex<ConnectionType>>) {
+    let mut c = conn.lock().unwrap();
+    let _ = c.close();
+    *c = ConnectionType::Disconnected("Not connected. Waiting for reconnection.".to_string());
+}
+
+//
+// 送信側の接続と受信スレッドへの連絡路
+//
+struct Link {
+    conn: Arc<Mutex<ConnectionType>>,
+    tx: Sender<Command>,
+    log: SharedLog,
+}
+
+impl Link {
+    // MSX へ送信する
+    // 送信に失敗した場合は受信スレッドに再接続を依頼する
+    fn send(&self, buf: &[u8]) -> error::Result<()> {
+        if let Some(ref mut l) = *self.log.lock().unwrap() {
+            l.bytes(sessionlog::Direction::Tx, buf);
+        }
+        let result = self.conn.lock().unwrap().write(buf);
+        if let Err(e) = result {
+            let _ = self.tx.send(Command::Reconnect);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    // 受信スレッドへ通知する
+    fn command(&self, command: Command) -> error::Result<()> {
+        self.tx.send(command).map_err(|_| ConnectionError::ReceiverStopped.into())
+    }
+
+    // 受信した行を受け取れるようにする
+    // 受信スレッドが受け付けるまで待つので、この後に送信したものへの応答は取りこぼさない
+    fn watch(&self) -> error::Result<Receiver<String>> {
+        let (tx, rx) = channel();
+        let (ack_tx, ack_rx) = channel();
+        self.command(Command::Watch(tx, ack_tx))?;
+        ack_rx.recv().map_err(|_| ConnectionError::ReceiverStopped)?;
+        Ok(rx)
+    }
+
+    fn unwatch(&self) {
+        let _ = self.tx.send(Command::Unwatch);
+    }
+}
+
+//
+// 入力された一行を処理する
+// ターミナルコマンドでなければ MSX へ送信する
+// #quit の場合は false を返す
+//
+fn exec_line(line: &str, msxterm: &mut Msxterm, rl: &mut MsxEditor, link: &Link) -> error::Result<bool> {
+    if line.starts_with("#quit") {
+        // 接続終了
+        let _ = link.command(Command::Quit);
+        let _ = link.conn.lock().unwrap().close();
+        return Ok(false);
+    }
+    if line.starts_with("#hex") {
+        let hex = hex2u8(line)?;
+        link.send(&hex)?;
+        return Ok(true);
+    }
+    if line.starts_with("#dump_on") {
+        link.command(Command::DumpModeOn)?;
+        println!("Output dump mode On");
+        return Ok(true);
+    }
+    if line.starts_with("#dump_off") {
+        link.command(Command::DumpModeOff)?;
+        println!("Output dump mode Off");
+        return Ok(true);
+    }
+    if line.starts_with("#lowsend_on") {
+        msxterm.lower_mode = true;
+        println!("Lower Case send mode On");
+        return Ok(true);
+    }
+    if line.starts_with("#lowsend_off") {
+        msxterm.lower_mode = false;
+        println!("Lower Case send mode Off");
+        return Ok(true);
+    }
+    if line.starts_with("#kanji_on") {
+        link.command(Command::KanjiModeOn)?;
+        msxterm.kanji_mode = true;
+        println!("Kanji mode On");
+        return Ok(true);
+    }
+    if line.starts_with("#kanji_off") {
+        link.command(Command::KanjiModeOff)?;
+        msxterm.kanji_mode = false;
+        println!("Kanji mode Off");
+        return Ok(true);
+    }
+    if line.starts_with("#screen_emu") {
+        match line.split_whitespace().nth(1) {
+            Some("on") => {
+                link.command(Command::ScreenEmuOn)?;
+                msxterm.screen_emu = true;
+            },
+            Some("off") => {
+                link.command(Command::ScreenEmuOff)?;
+                msxterm.screen_emu = false;
+            },
+            None => {},
+            Some(_) => return Err(MsxError::Command("Usage: #screen_emu on|off".to_string())),
+        }
+        println!("Screen emulation {}", if msxterm.screen_emu { "On" } else { "Off" });
+        return Ok(true);
+    }
+    if line.starts_with("#raw") {
+        if let Some(chord) = line.split_whitespace().nth(1) {
+            msxterm.raw_escape = rawmode::KeyChord::parse(chord).map_err(MsxError::Command)?;
+        }
+        println!("Raw mode On (press {} to return)", msxterm.raw_escape);
+        link.command(Command::RawModeOn)?;
+        let result = rawmode::run(&msxterm.raw_escape, |key| {
+            let code = match key {
+                rawmode::RawKey::Code(c) => Ok(vec![c]),
+                rawmode::RawKey::Char(c) => msxterm.encode_char(c),
+            };
+            if let Err(e) = code.and_then(|code| link.send(&code)) {
+                write_tty(&format!("\r\n{}\r\n", e));
+            }
+        });
+        link.command(Command::RawModeOff)?;
+        println!();
+        println!("Raw mode Off");
+        result?;
+        return Ok(true);
+    }
+    if line.starts_with("#paced") {
+        match line.split_whitespace().nth(1) {
+            Some("on") => msxterm.paced = true,
+            Some("off") => msxterm.paced = false,
+            None => {},
+            Some(_) => return Err(MsxError::Command("Usage: #paced on|off".to_string())),
+        }
+        println!("Paced send {}", if msxterm.paced { "On" } else { "Off" });
+        return Ok(true);
+    }
+    if line.starts_with("#mirror") {
+        let mut args = line.split_whitespace().skip(1);
+        match args.next() {
+            Some("on") => {
+                if let Some(w) = args.next() {
+                    msxterm.screen_width = parse_width(w)?;
+                }
+                link.command(Command::MirrorOn(msxterm.screen_width))?;
+            },
+            Some("off") => link.command(Command::MirrorOff)?,
+            _ => return Err(MsxError::Command("Usage: #mirror on [32|40|80] | off".to_string())),
+        }
+        return Ok(true);
+    }
+    if line.starts_with("#serial") {
+        let param = line.trim_start_matches("#serial");
+        let mut sp = msxterm.serial_param;
+        sp.parse_args(param).map_err(MsxError::Command)?;
+        if sp != msxterm.serial_param {
+            link.conn.lock().unwrap().set_serial_param(&sp)?;
+            msxterm.serial_param = sp;
+            link.command(Command::SerialParam(sp))?;
+        }
+        println!("Serial {}", msxterm.serial_param);
+        return Ok(true);
+    }
+    if line.starts_with("#emacs") {
+        rl.set_edit_mode(EditMode::Emacs);
+        return Ok(true);
+    }
+    if line.starts_with("#vi") {
+        rl.set_edit_mode(EditMode::Vi);
+        return Ok(true);
+    }
+    if line.starts_with("#clear_history") {
+        rl.clear_history()?;
+        println!("History is cleared.");
+        return Ok(true);
+    }
+    if line.starts_with("#new") {
+        msxterm.prog_buff.clear();
+        println!("Program Buffer is cleared.");
+        return Ok(true);
+    }
+    if line.starts_with("#load") {
+        let basic = load(line)?;
+        if msxterm.paced {
+            // 一行ずつ MSX の応答を待ちながら送る
+            let mut lines = Vec::new();
+            for bl in basic {
+                let tmp = bl.trim().to_string();
+                if tmp.is_empty() {
+                    continue;
+                }
+                msxterm.parse_basic(tmp.as_str());
+                rl.add_history_entry(tmp.as_str())?;
+                lines.push(tmp);
+            }
+            let count = transfer::send_lines(msxterm, link, &lines)?;
+            println!("{} lines loaded.", count);
+            return Ok(true);
+        }
+        let mut ld_program = "".to_string();
+        for bl in basic {
+            let mut tmp = bl.trim().to_string();
+            msxterm.parse_basic(tmp.as_str());
+            rl.add_history_entry(tmp.as_str())?;
+            tmp.push(C_CR);
+            ld_program.push_str(&tmp);
+        }
+        // 入力した行と同じく MSX 側の文字コードに変換して送る
+        link.send(&msxterm.encode(&ld_program)?)?;
+        println!("Ok");
+        return Ok(true);
+    }
+    if line.starts_with("#reload_from") {
+        let listing = transfer::capture_list(msxterm, link)?;
+        msxterm.clear_basic();
+        for l in &listing {
+            msxterm.parse_basic(l);
+            rl.add_history_entry(l.as_str())?;
+        }
+        println!("{} lines reloaded from MSX.", listing.len());
+        return Ok(true);
+    }
+    if line.starts_with("#reload_to") {
+        let lines: Vec<String> = msxterm.prog_buff.iter().map(|(n, l)| format!("{} {}", n, l)).collect();
+        // -y / --yes なら確認しない (スクリプト用)
+        let yes = line.split_whitespace().skip(1).any(|a| a == "-y" || a == "--yes");
+        if !yes {
+            let answer = rl.readline(&format!("Replace the MSX program with {} lines? (y/N) ", lines.len()))?;
+            if !answer.trim().eq_ignore_ascii_case("y") {
+                println!("Canceled.");
+                return Ok(true);
+            }
+        }
+        let count = transfer::reload_to(msxterm, link, &lines)?;
+        println!("{} lines sent to MSX.", count);
+        return Ok(true);
+    }
+    if line.starts_with("#diff") {
+        let remote = diff::parse_listing(&transfer::capture_list(msxterm, link)?);
+        let diffs = diff::diff_program(&msxterm.prog_buff, &remote);
+        if diffs.is_empty() {
+            println!("No differences.");
+        } else {
+            diff::print_diff(&diffs, &msxterm.color);
+        }
+        return Ok(true);
+    }
+    if line.starts_with("#sync") {
+        let remote = diff::parse_listing(&transfer::capture_list(msxterm, link)?);
+        let lines: Vec<String> = diff::diff_program(&msxterm.prog_buff, &remote).iter()
+            .map(|d| d.sync_line())
+            .collect();
+        if lines.is_empty() {
+            println!("No differences.");
+            return Ok(true);
+        }
+        let count = transfer::send_lines(msxterm, link, &lines)?;
+        println!("{} lines synced to MSX.", count);
+        return Ok(true);
+    }
+    if line.starts_with("#renum") {
+        let param = renum::RenumParam::parse(line.trim_start_matches("#renum")).map_err(MsxError::Command)?;
+        let result = renum::renum(&msxterm.prog_buff, param).map_err(MsxError::Command)?;
+        for (num, target) in &result.undefined {
+            println!("Undefined line {} in {}", target, num);
+        }
+        println!("{} lines renumbered.", result.program.len());
+        msxterm.prog_buff = result.program;
+        // MSX 側も同じように付け直す
+        let mut command = param.to_command();
+        command.push(C_CR);
+        link.send(&msxterm.encode(&command)?)?;
+        return Ok(true);
+    }
+    if line.starts_with("#check") {
+        let problems = check::check(&msxterm.prog_buff);
+        for (num, problem) in &problems {
+            println!("{} {}", highlight::colored(msxterm.color.line, &num.to_string()), problem);
+        }
+        if problems.is_empty() {
+            println!("No problems found.");
+        } else {
+            println!("{} problems found.", problems.len());
+        }
+        return Ok(true);
+    }
+    if line.starts_with("#log") {
+        let mut session_log = link.log.lock().unwrap();
+        let mut args = line.split_whitespace().skip(1);
+        match args.next() {
+            None => {},
+            Some("off") => *session_log = None,
+            Some(file) => {
+                let mut timestamp = false;
+                let mut raw = false;
+                for option in args {
+                    match option {
+                        "--time" => timestamp = true,
+                        "--raw" => raw = true,
+                        _ => return Err(MsxError::Command("Usage: #log <file> [--time] [--raw] | off".to_string())),
+                    }
+                }
+                *session_log = Some(sessionlog::SessionLog::open(std::path::Path::new(file), timestamp, raw)?);
+            },
+        }
+        match *session_log {
+            Some(ref l) if l.is_raw() => println!("Logging to {} (raw: {})",
+                l.path().display(), sessionlog::raw_path(l.path()).display()),
+            Some(ref l) => println!("Logging to {}", l.path().display()),
+            None => println!("Logging Off"),
+        }
+        return Ok(true);
+    }
+    if line.starts_with("#record") {
+        match line.split_whitespace().nth(1) {
+            None => {},
+            Some("off") => {
+                link.command(Command::RecordOff)?;
+                msxterm.recording = None;
+            },
+            Some(file) => {
+                let path = PathBuf::from(file);
+                let recorder = cast::Recorder::create(&path, msxterm.screen_width)?;
+                link.command(Command::RecordOn(recorder))?;
+                msxterm.recording = Some(path);
+            },
+        }
+        match msxterm.recording {
+            Some(ref path) => println!("Recording to {}", path.display()),
+            None => println!("Recording Off"),
+        }
+        return Ok(true);
+    }
+    if line.starts_with("#run_script") {
+        let path = path_arg(line)?;
+        script::run_file(&path, msxterm, rl, link)?;
+        println!("Script finished.");
+        return Ok(true);
+    }
+    if line.starts_with("#list") {
+        for history in msxterm.print_basic(0, 65530) {
+            rl.add_history_entry(history)?;
+        }
+        return Ok(true);
+    }
+    if line.starts_with("#save") {
+        if line.contains(" --tokenized") {
+            msxterm.save_tokenized(&line.replacen(" --tokenized", "", 1))?;
+        } else {
+            msxterm.save_program(line)?;
+        }
+        println!("Ok");
+        return Ok(true);
+    }
+
+    // 変換できない場合はプログラムバッファにも登録しない
+    let mut tmp2 = line.to_string();
+    tmp2.push(C_CR);
+    let code = msxterm.encode(&tmp2)?;
+    msxterm.parse_basic(line);
+    link.send(&code)?;
+
+    // ダイレクトモードの WIDTH 命令で画面モデルの桁数を合わせる
+    let upper = line.trim().to_uppercase();
+    if let Some(w) = upper.strip_prefix("WIDTH") {
+        if let Ok(width) = parse_width(w) {
+            msxterm.screen_width = width;
+            link.command(Command::ScreenWidth(width))?;
+        }
+    }
+    Ok(true)
+}
+
+// WIDTH の桁数 (1～80)
+fn parse_width(w: &str) -> error::Result<usize> {
+    match w.trim().parse::<usize>() {
+        Ok(width) if (1..=80).contains(&width) => Ok(width),
+        _ => Err(MsxError::Command(format!("Bad width: {}", w.trim()))),
+    }
+}
+
+//
+// 接続して受信スレッドを動かす
+// printer がない場合 (端末がない場合) は標準出力に書く
+//
+fn connect<P: ExternalPrinter + Send + 'static>(
+    target: String,
+    msxterm: &Msxterm,
+    session_log: Option<sessionlog::SessionLog>,
+    printer: Option<P>,
+) -> std::result::Result<(Link, thread::JoinHandle<()>), ConnectionError> {
+    let conn = connection::create_connection(&target, &msxterm.serial_param)?;
+    let conn_read = conn.try_clone()?;
+
+    // 通信スレッドとメインスレッド間でやりとりするチャンネルを作成する
+    let (tx, rx): (Sender<Command>, Receiver<Command>) = channel();
+
+    // 受信用スレッドを作成
+    // 送信側は再接続時に受信スレッドから差し替えられるので共有する
+    let link = Link { conn: Arc::new(Mutex::new(conn)), tx, log: Arc::new(Mutex::new(session_log)) };
+    let conn_shared = Arc::clone(&link.conn);
+    let log_shared = Arc::clone(&link.log);
+    let serial_param = msxterm.serial_param;
+    let receive_thread = thread::spawn(move || {
+        match printer {
+            Some(p) => receive_loop(conn_read, conn_shared, log_shared, target, serial_param, rx, p),
+            None => receive_loop(conn_read, conn_shared, log_shared, target, serial_param, rx, StdoutPrinter::default()),
+        }
+    });
+    // プロファイルで指定したモードを受信スレッドにも伝える
+    if msxterm.kanji_mode {
+        let _ = link.command(Command::KanjiModeOn);
+    }
+    if msxterm.dump_mode {
+        let _ = link.command(Command::DumpModeOn);
+    }
+    Ok((link, receive_thread))
+}
+
+//
+// msxterm run: 端末を使わずにプログラムを送って RUN し、出力を標準出力に書く
+// 終了コードを返す (0 正常, 1 "error in", 2 タイムアウト, 3 それ以外の失敗)
+//
+fn run_batch(args: &Args, msxterm: &mut Msxterm) -> i32 {
+    let Some(SubCommand::Run { ref file, ref until, timeout, .. }) = args.command else {
+        return 3;
+    };
+    let fail = |e: &dyn std::fmt::Display| {
+        eprintln!("{}", e);
+        3
+    };
+    let settings = match args.settings() {
+        Ok(settings) => settings,
+        Err(e) => return fail(&e),
+    };
+    match serial_param(&settings) {
+        Ok(sp) => msxterm.serial_param = sp,
+        Err(e) => return fail(&e),
+    }
+    msxterm.kanji_mode = settings.kanji.unwrap_or(false);
+    let until = match regex::Regex::new(until) {
+        Ok(re) => re,
+        Err(e) => return fail(&e),
+    };
+    let timeout = match Duration::try_from_secs_f64(timeout) {
+        Ok(t) => t,
+        Err(e) => return fail(&e),
+    };
+    let lines: Vec<String> = match load_file(file) {
+        Ok(lines) => lines.iter().map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect(),
+        Err(e) => return fail(&e),
+    };
+
+    let target = settings.target.clone().unwrap_or_default();
+    eprintln!("Connecting... {}", target);
+    let (link, receive_thread) = match connect(target, msxterm, None, Some(batch::StderrPrinter::default())) {
+        Ok(c) => c,
+        Err(e) => return fail(&format!("Failed to connect. {}", e)),
+    };
+    eprintln!("connected.");
+    let result = batch::run_program(msxterm, &link, &lines, &until, timeout);
+    let _ = link.command(Command::Quit);
+    let _ = receive_thread.join();
+    match result {
+        Ok(result) => {
+            for line in &result.output {
+                println!("{}", line);
+            }
+            if result.outcome == batch::Outcome::Timeout {
+                eprintln!("Timeout after {} sec.", timeout.as_secs_f64());
+            }
+            result.outcome.exit_code()
+        },
+        Err(e) => fail(&e),
+    }
+}
 
 fn main() -> Result<()> {
     // 変数初期化
@@ -316,112 +1598,119 @@ fn main() -> Result<()> {
 
     // コマンドライン引数取得
     let args = Args::parse();
+    if let Some(SubCommand::Replay { ref file, speed, idle_limit, kanji }) = args.command {
+        if let Err(e) = cast::replay(file, speed, idle_limit, kanji) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(SubCommand::Run { .. }) = args.command {
+        std::process::exit(run_batch(&args, &mut msxterm));
+    }
     if args.port_list {
-        serial_port_list();
+        if let Err(e) = serial_port_list() {
+            eprintln!("{}", e);
+        }
         return Ok(());
     }
-    let target = match args.target {
-        Some(target) => {
-            target
+    // 起動前の失敗を表示する (--script の場合は終了コード 1 で終わる)
+    let abort = |e: &dyn std::fmt::Display| -> Result<()> {
+        eprintln!("{}", e);
+        if args.script.is_some() {
+            std::process::exit(1);
+        }
+        Ok(())
+    };
+    // 設定ファイルのプロファイルにコマンドラインの指定を重ねる
+    let settings = match args.settings() {
+        Ok(settings) => settings,
+        Err(e) => {
+            return abort(&e);
+        }
+    };
+    match serial_param(&settings) {
+        Ok(sp) => msxterm.serial_param = sp,
+        Err(e) => {
+            return abort(&e);
+        }
+    }
+    match rawmode::KeyChord::parse(settings.raw_escape.as_deref().unwrap_or("ctrl-]")) {
+        Ok(chord) => msxterm.raw_escape = chord,
+        Err(e) => {
+            return abort(&e);
+        }
+    }
+    if let Some(ref name) = settings.color {
+        match highlight::Scheme::by_name(name) {
+            Some(scheme) => msxterm.color = scheme,
+            None => {
+                return abort(&format!("Unknown color scheme: {}", name));
+            }
+        }
+    }
+    msxterm.kanji_mode = settings.kanji.unwrap_or(false);
+    msxterm.dump_mode = settings.dump.unwrap_or(false);
+    msxterm.paced = settings.paced.unwrap_or(false);
+    let history_file = settings.history.clone().unwrap_or("history.txt".to_string());
+    let session_log = match args.log {
+        Some(ref path) => match sessionlog::SessionLog::open(path, args.time, args.raw) {
+            Ok(l) => Some(l),
+            Err(e) => {
+                return abort(&e);
+            }
         },
-        _ => {
-             "".to_string()    
-         }
+        None => None,
     };
+    let target = settings.target.clone().unwrap_or_default();
 /*
     println!("file {}", args.file);
-    println!("serial {}", args.serial);
     println!("portlist {}", args.port_list);
 */
     // エディタを生成
-    let mut rl = DefaultEditor::new()?;
-    match args.editor {
-        Some(ed) =>  {
-            if ed.eq("emacs") {
-                rl.set_edit_mode(EditMode::Emacs);
-            } else if ed.eq("vi") {
-                rl.set_edit_mode(EditMode::Vi);
-            }
-        },
-        _ => {}
+    let mut rl = MsxEditor::new()?;
+    rl.set_helper(Some(MsxHelper::new(msxterm.t_com.clone(), msxterm.color)));
+    if let Some(ref ed) = settings.editor {
+        if ed.eq("emacs") {
+            rl.set_edit_mode(EditMode::Emacs);
+        } else if ed.eq("vi") {
+            rl.set_edit_mode(EditMode::Vi);
+        }
     }
 
-    let mut printer = rl.create_external_printer()?;
-    if rl.load_history(&args.file).is_err() {
+    let printer = rl.create_external_printer().ok();
+    if rl.load_history(&history_file).is_err() {
         println!("No previous history.");
     }
 
-    // ソケットを接続
-    let server_address = target.clone();
-    println!("Connecting... {}", server_address);
-
-/*
-    let mut conn = connection::create_connection(&target);
-    let mut conn_read  = Arc::new(Mutec::new(conn));
-    let mut conn_write = Arc::new(Mutec::new(conn));
-*/
-
-    let mut stream;
-    let r = TcpStream::connect(server_address);
-    match r {
-        Ok(s) => {
-            stream = s;
-            println!("connected.");
-        },
-        Err(_) => {
-            eprintln!("Failed to connect.");
-            return Ok(());
-        }, 
-    }
-
-    // 通信スレッドとメインスレッド間でやりとりするチャンネルを作成する
-    let (tx, rx): (Sender<Command>, Receiver<Command>) = channel();
+    // TCP/IP またはシリアルポートに接続
+    println!("Connecting... {}", target);
+    let (link, receive_thread) = match connect(target.clone(), &msxterm, session_log, printer) {
+        Ok(c) => c,
+        Err(e) => {
+            return abort(&format!("Failed to connect. {}", e));
+        }
+    };
+    println!("connected.");
 
-    // 受信用スレッドを作成
-    let stream_clone = stream.try_clone().expect("Failed to clone stream");
-    let receive_thread = thread::spawn(move || {
-        let mut dump_mode = false;
-        let mut kanji_mode = false;
-        let mut reader = std::io::BufReader::new(&stream_clone);
-        loop {
-            if let Ok(command) = rx.recv_timeout(Duration::from_millis(1)) {
-                match command {
-                    Command::DumpModeOn => dump_mode = true,
-                    Command::DumpModeOff => dump_mode = false,
-                    Command::KanjiModeOn => kanji_mode = true,
-                    Command::KanjiModeOff => kanji_mode = false,
-                }
-            }
-            let mut byte_buff: Vec<u8> = [0x00_u8; 0].to_vec();
-            let result = reader.read_until(U_LF, &mut byte_buff);
-            match result {
-                Ok(size) => {
-                    if size == 0 {
-                        printer.print("Tcp disconnect".to_string()).expect("External print failure");
-                        break;
-                    }    
-                },
-                Err(e) => {
-                    printer.print(e.to_string()).expect("External print failure");
-                    break;
-                }
-            }
-            if dump_mode {
-                let recv_buff = dump_hex(byte_buff);
-                printer.print(recv_buff).expect("External print failure");
-            } else {
-                let recv_buff = if kanji_mode {
-                    msxcode::msx_kanji_to_string(byte_buff)
-                } else {
-                    msxcode::msx_ascii_to_string(byte_buff)
-                };
-                printer.print(recv_buff).expect("External print failure");    
-            }
+    // --script の場合はスクリプトを実行して終了する
+    if let Some(ref path) = args.script {
+        let result = script::run_file(path, &mut msxterm, &mut rl, &link);
+        let _ = link.command(Command::Quit);
+        let _ = receive_thread.join();
+        if let Err(e) = result {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
-    });
+        return Ok(());
+    }
 
     // エディタ入力とコマンド送信のメインループ
     'input:loop {
+        // GOTO の後ろで補完する行番号
+        if let Some(helper) = rl.helper_mut() {
+            helper.lines = msxterm.prog_buff.keys().copied().collect();
+        }
         let readline = rl.readline("> ");
         match readline {
             Ok(tmpl) => {
@@ -429,134 +1718,30 @@ fn main() -> Result<()> {
                 let b = tmpl.as_str().replace("\r\n","\r").replace('\n',"\r");
                 let lines: Vec<&str> = b.split(C_CR).collect();
                 for line in lines {
-                    rl.add_history_entry(line)?;
-
-                    if line.starts_with("#quit") {
-                        // TCP 接続終了
-                        stream.shutdown(Shutdown::Both).expect("Shutdown Error");
-                        break 'input;
-                    }
-                    if line.starts_with("#hex") {
-                        let hex = hex2u8(line);
-                        stream.write(&hex).expect("Failed to write to server");
-                        continue;
+                    if let Err(e) = rl.add_history_entry(line) {
+                        println!("{}", MsxError::from(e));
                     }
-                    if line.starts_with("#dump_on") {
-                        tx.send(Command::DumpModeOn).expect("Thread sync Error");
-                        println!("Output dump mode On");
-                        continue;             
+                    match exec_line(line, &mut msxterm, &mut rl, &link) {
+                        Ok(true) => {},
+                        Ok(false) => break 'input,
+                        Err(e) => println!("{}", e),
                     }
-                    if line.starts_with("#dump_off") {
-                        tx.send(Command::DumpModeOff).expect("Thread sync Error");
-                        println!("Output dump mode Off");
-                        continue;             
-                    }
-                    if line.starts_with("#lowsend_on") {
-                        msxterm.lower_mode = true;
-                        println!("Lower Case send mode On");
-                        continue;
-                    }
-                    if line.starts_with("#lowsend_off") {
-                        msxterm.lower_mode = false;
-                        println!("Lower Case send mode Off");
-                        continue;
-                    }
-                    if line.starts_with("#kanji_on") {
-                        tx.send(Command::KanjiModeOn).expect("Thread sync Error");
-                        msxterm.kanji_mode = true;
-                        println!("Kanji mode On");
-                        continue;                        
-                    }
-                    if line.starts_with("#kanji_off") {
-                        tx.send(Command::KanjiModeOff).expect("Thread sync Error");
-                        msxterm.kanji_mode = false;
-                        println!("Kanji mode Off");
-                        continue;
-                    }
-                    if line.starts_with("#emacs") {
-                        rl.set_edit_mode(EditMode::Emacs);
-                        continue;
-                    }
-                    if line.starts_with("#vi") {
-                        rl.set_edit_mode(EditMode::Vi);
-                        continue;
-                    }
-                    if line.starts_with("#clear_history") {
-                        rl.clear_history().unwrap();
-                        println!("History is cleared.");
-                        continue;
-                    }
-                    if line.starts_with("#new") {
-                        msxterm.prog_buff.clear();
-                        println!("Program Buffer is cleared.");
-                        continue;
-                    }
-                    if line.starts_with("#load") {
-                        match load(line) {
-                            Ok(basic) => {
-                                let mut ld_program = "".to_string();
-                                for bl in basic {
-                                    let mut tmp = bl.trim().to_string();
-                                    msxterm.parse_basic(tmp.as_str());
-                                    rl.add_history_entry(tmp.as_str())?;
-                                    tmp.push(C_CR);
-                                    ld_program.push_str(&tmp);
-                                }
-                                if msxterm.lower_mode {
-                                    ld_program = lower_program(&ld_program);
-                                }
-                                stream
-                                .write(ld_program.as_bytes())
-                                .expect("Failed to write to server");
-                            },
-                            Err(e) => {
-                                println!("{}", e);
-                            }
-                        }
-                        println!("Ok");
-                        continue;
-                    }
-                    if line.starts_with("#list") {
-                        let cols = line.split(' ');
-                        for history in msxterm.print_basic(0, 65530) {
-                            rl.add_history_entry(history)?;
-                        }
-                        continue;
-                    }
-                    if line.starts_with("#save") {
-                        msxterm.save_program(line);
-                        println!("Ok");
-                        continue;
-                    }
-
-                    msxterm.parse_basic(line);
-
-                    let mut tmp2 = line.to_string();
-                    tmp2.push(C_CR);
-                    if msxterm.lower_mode {
-                        tmp2 = lower_program(&tmp2);
-                    }
-
-                    let faces_code = if msxterm.kanji_mode {
-                        msxcode::utf8_to_msx_kanji(tmp2.as_str())
-                    } else {
-                        msxcode::utf8_msx_jp_code(tmp2.as_str())
-                    };
-
-                    stream
-                        .write(&faces_code).expect("Failed to write");
                 }
             }
             Err(ReadlineError::Interrupted) => {
                 // break 送信
                 let buf = vec![U_BREAK];
-                stream.write(&buf).expect("Failed to write");
+                if let Err(e) = link.send(&buf) {
+                    println!("{}", e);
+                }
                 continue;
             }
             Err(ReadlineError::Eof) => {
                 // BS 送信
                 let buf = vec![U_PAUSE];
-                stream.write(&buf).expect("Failed to write");
+                if let Err(e) = link.send(&buf) {
+                    println!("{}", e);
+                }
                 continue;
             }
             Err(err) => {
@@ -566,18 +1751,19 @@ fn main() -> Result<()> {
         }
     }
     // 受信スレッド終了
-    receive_thread
-        .join()
-        .expect("Failed to join receive thread");
+    let _ = link.command(Command::Quit);
+    if receive_thread.join().is_err() {
+        eprintln!("Failed to join receive thread");
+    }
 
     // 履歴ファイル記録
-    match rl.save_history(& args.file) 
+    match rl.save_history(&history_file) 
     {
         Ok(_) => {
-            println!("history save to {}", args.file);
+            println!("history save to {}", history_file);
         },
         Err(e) => {
-            println!("{}", e.to_string());
+            println!("{}", e);
         }
     }
     Ok(())
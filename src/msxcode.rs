@@ -67,22 +67,7 @@ const MSX_TO_GRAPH: [char;256] = [
 // MSX ASCII のベクターから UTF-8 文字列に変換
 pub fn msx_ascii_to_string(uv: Vec<u8>) -> String
 {
-    let mut graph = false;
-    let mut cv:String = "".to_string();
-    for u in uv {
-        let s = u as usize;
-        if u == 1 {
-            graph = true;
-        } else if graph {
-            let c = MSX_TO_GRAPH[s];
-            cv.push(c);
-            graph = false;
-        } else {
-            let c =  MSX_TO_UTF8[s];
-            cv.push(c);
-        }
-    }
-    cv
+    MsxDecoder::new(false).decode(&uv)
 }
 
 #[test]
@@ -101,10 +86,86 @@ pub fn msx_kanji_to_string(uv: Vec<u8>) -> String
     res.into_owned()
 }
 
-pub fn utf8_to_msx_kanji(input: &str) -> Vec<u8>
+//
+// 受信データを逐次 UTF-8 に変換するデコーダ
+// 読み込みの切れ目で分断された GRAPH プレフィックス (0x01) や
+// Shift-JIS の第1バイトは次の decode まで持ち越す
+//
+pub struct MsxDecoder {
+    kanji_mode: bool,
+    graph: bool,
+    sjis: encoding_rs::Decoder,
+}
+
+impl MsxDecoder {
+    pub fn new(kanji_mode: bool) -> MsxDecoder {
+        MsxDecoder {
+            kanji_mode,
+            graph: false,
+            sjis: SHIFT_JIS.new_decoder_without_bom_handling(),
+        }
+    }
+
+    // モードを切り替えて持ち越し中のバイトを破棄する
+    pub fn set_kanji_mode(&mut self, kanji_mode: bool) {
+        *self = MsxDecoder::new(kanji_mode);
+    }
+
+    pub fn kanji_mode(&self) -> bool {
+        self.kanji_mode
+    }
+
+    pub fn decode(&mut self, uv: &[u8]) -> String
+    {
+        let mut cv:String = "".to_string();
+        if self.kanji_mode {
+            let len = self.sjis.max_utf8_buffer_length(uv.len()).unwrap_or(uv.len() * 3 + 4);
+            cv.reserve(len);
+            let _ = self.sjis.decode_to_string(uv, &mut cv, false);
+            return cv;
+        }
+        for &u in uv {
+            let s = u as usize;
+            if u == 1 {
+                self.graph = true;
+            } else if self.graph {
+                cv.push(MSX_TO_GRAPH[s]);
+                self.graph = false;
+            } else {
+                cv.push(MSX_TO_UTF8[s]);
+            }
+        }
+        cv
+    }
+}
+
+#[test]
+fn msx_decoder_test()
 {
-    let (res,_,_) = SHIFT_JIS.encode(input);
-    res.into_owned()
+    // 分割された GRAPH 文字
+    let mut dec = MsxDecoder::new(false);
+    let mut s = dec.decode(&[0x41, 0x01]);
+    s.push_str(&dec.decode(&[0x41, 0x42]));
+    assert_eq!(s, msx_ascii_to_string(vec![0x41, 0x01, 0x41, 0x42]));
+
+    // 分割された Shift-JIS
+    let mut dec = MsxDecoder::new(true);
+    assert_eq!(dec.decode(&[0x82, 0x6c, 0x82]), "Ｍ");
+    assert_eq!(dec.decode(&[0x72, 0x4f, 0x6b]), "ＳOk");
+}
+
+// UTF-8 から MSX KANJI (Shift-JIS) へ変換
+// 変換できない文字があればその文字を返す
+pub fn utf8_to_msx_kanji(input: &str) -> Result<Vec<u8>, String>
+{
+    let (res,_,had_errors) = SHIFT_JIS.encode(input);
+    if had_errors {
+        let bad: String = input.chars()
+            .filter(|c| SHIFT_JIS.encode(c.encode_utf8(&mut [0; 4])).2)
+            .collect();
+        return Err(bad);
+    }
+    Ok(res.into_owned())
 }
 
 
@@ -115,6 +176,10 @@ fn msx_kanji_test()
     let s = msx_kanji_to_string(uv);
     println!("{}", s);
     assert_eq!(s,"ＭＳＸの漢字");
+
+    let uv = utf8_to_msx_kanji("ＭＳＸの漢字").unwrap();
+    assert_eq!(uv, vec![0x82,0x6c,0x82,0x72,0x82,0x77,0x82,0xcc,0x8a,0xbf,0x8e,0x9a]);
+    assert_eq!(utf8_to_msx_kanji("A😀B"), Err("😀".to_string()));
 }
 
 
@@ -0,0 +1,259 @@
+// BASIC Source Preprocessor Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// 行番号なしで書いたソースを MSX-BASIC のプログラムに変換する
+//
+//   @label:            飛び先 (次の行の行番号になる)
+//   GOTO @label        ラベルの参照
+//   ' コメント          取り除く
+//   #include "file"    ファイルの取り込み
+//   行頭の字下げ        取り除く
+//
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use crate::error::{self, MsxError};
+use crate::tokenize;
+
+// 振る行番号
+const LINE_START: u32 = 10;
+const LINE_STEP: u32 = 10;
+
+/// ソースの一行 (エラー表示用に場所を持つ)
+#[derive(Debug, Clone)]
+pub struct SourceLine {
+    pub file: String,
+    pub line_no: usize,
+    pub text: String,
+}
+
+impl SourceLine {
+    fn error(&self, msg: String) -> MsxError {
+        MsxError::Source(format!("{}:{}: {}", self.file, self.line_no, msg))
+    }
+}
+
+// 行番号なしのソースか
+// コメントと #include 以外の最初の行が数字で始まらなければそう判断する
+pub fn is_source(lines: &[String]) -> bool {
+    lines.iter()
+        .map(|l| l.trim())
+        .find(|l| !l.is_empty() && !l.starts_with('\'') && !l.starts_with("#include"))
+        .is_some_and(|l| !l.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+//
+// ファイルを読み込んで #include を展開する
+// stack は取り込み中のファイル (循環の検出用)
+//
+fn read_source(path: &Path, stack: &mut Vec<PathBuf>, out: &mut Vec<SourceLine>) -> error::Result<()> {
+    let file_err = |e| MsxError::File(path.display().to_string(), e);
+    let canonical = path.canonicalize().map_err(file_err)?;
+    if stack.contains(&canonical) {
+        return Err(MsxError::Source(format!("Recursive include: {}", path.display())));
+    }
+    let text = std::fs::read_to_string(path).map_err(file_err)?;
+    stack.push(canonical);
+    for (n, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if let Some(arg) = trimmed.strip_prefix("#include") {
+            let name = arg.trim().trim_matches('"');
+            let sl = SourceLine { file: path.display().to_string(), line_no: n + 1, text: String::new() };
+            if name.is_empty() {
+                return Err(sl.error("Usage: #include \"file\"".to_string()));
+            }
+            // 取り込むファイルは取り込む側のファイルからの相対パス
+            let inc = path.parent().unwrap_or(Path::new(".")).join(name);
+            read_source(&inc, stack, out)?;
+            continue;
+        }
+        out.push(SourceLine { file: path.display().to_string(), line_no: n + 1, text: line.to_string() });
+    }
+    stack.pop();
+    Ok(())
+}
+
+// ' 以降を取り除く (文字列の中は除く)
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (n, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '\'' if !quoted => return &line[..n],
+            _ => {},
+        }
+    }
+    line
+}
+
+fn is_label_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// 行頭の @label: を取り出す
+fn take_label(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix('@')?;
+    let len = rest.find(|c: char| !is_label_char(c)).unwrap_or(rest.len());
+    let (name, after) = rest.split_at(len);
+    let after = after.trim_start().strip_prefix(':')?;
+    if name.is_empty() {
+        return None;
+    }
+    Some((name, after.trim_start()))
+}
+
+// @label の参照を行番号に置き換える
+// 文字列と REM の後ろはそのまま
+fn resolve_labels(sl: &SourceLine, code: &str, labels: &HashMap<String, u32>) -> error::Result<String> {
+    let mut out = String::with_capacity(code.len());
+    let mut quoted = false;
+    // 変数名の途中 (PREMIUM の REM は命令ではない)
+    let mut in_name = false;
+    let mut chars = code.char_indices().peekable();
+    while let Some((n, c)) = chars.next() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        if !quoted && !in_name && c.is_ascii_alphabetic()
+            && tokenize::match_keyword(&code.as_bytes()[n..]).is_some_and(|(_, k)| k == "REM") {
+            out.push_str(&code[n..]);
+            break;
+        }
+        in_name = !quoted && c.is_ascii_alphanumeric();
+        if quoted || c != '@' {
+            out.push(c);
+            continue;
+        }
+        let rest = &code[n + 1..];
+        let len = rest.find(|c: char| !is_label_char(c)).unwrap_or(rest.len());
+        let name = &rest[..len];
+        if name.is_empty() {
+            out.push(c);
+            continue;
+        }
+        match labels.get(&name.to_lowercase()) {
+            Some(num) => out.push_str(&num.to_string()),
+            None => return Err(sl.error(format!("Undefined label @{}", name))),
+        }
+        for _ in 0..name.chars().count() {
+            chars.next();
+        }
+    }
+    Ok(out)
+}
+
+//
+// 行番号を振ってラベルを解決する
+// "行番号 命令" の形の行を返す
+//
+pub fn preprocess(source: &[SourceLine]) -> error::Result<Vec<String>> {
+    let mut labels: HashMap<String, u32> = HashMap::new();
+    // 次の行を待っているラベル
+    let mut pending: Vec<(String, &SourceLine)> = Vec::new();
+    let mut code_lines: Vec<(u32, &SourceLine, String)> = Vec::new();
+    let mut next = LINE_START;
+    for sl in source {
+        let mut code = strip_comment(&sl.text).trim();
+        while let Some((name, rest)) = take_label(code) {
+            if labels.contains_key(&name.to_lowercase()) || pending.iter().any(|(p, _)| p == &name.to_lowercase()) {
+                return Err(sl.error(format!("Duplicate label @{}", name)));
+            }
+            pending.push((name.to_lowercase(), sl));
+            code = rest;
+        }
+        if code.is_empty() {
+            continue;
+        }
+        if next > crate::renum::MAX_LINE {
+            return Err(sl.error("Too many lines".to_string()));
+        }
+        for (name, _) in pending.drain(..) {
+            labels.insert(name, next);
+        }
+        code_lines.push((next, sl, code.trim_end().to_string()));
+        next += LINE_STEP;
+    }
+    if let Some((name, sl)) = pending.first() {
+        return Err(sl.error(format!("No line after label @{}", name)));
+    }
+
+    let mut lines = Vec::new();
+    for (num, sl, code) in code_lines {
+        lines.push(format!("{} {}", num, resolve_labels(sl, &code, &labels)?));
+    }
+    Ok(lines)
+}
+
+// 行番号なしのソースファイルを読み込んで変換する
+pub fn load_source(path: &Path) -> error::Result<Vec<String>> {
+    let mut source = Vec::new();
+    read_source(path, &mut Vec::new(), &mut source)?;
+    preprocess(&source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(text: &str) -> Vec<SourceLine> {
+        text.lines().enumerate()
+            .map(|(n, l)| SourceLine { file: "test".to_string(), line_no: n + 1, text: l.to_string() })
+            .collect()
+    }
+
+    #[test]
+    fn test_preprocess() {
+        let src = source("
+' タイトル
+CLS
+@loop:
+    PRINT \"@home: 'x\" ' 表示
+    IF INKEY$=\"\" THEN GOTO @Loop ELSE GOSUB @sub
+    ON X GOTO @loop,@sub
+@sub: BEEP
+    RETURN  ' REM
+    REM @loop
+");
+        assert_eq!(preprocess(&src).unwrap(), [
+            "10 CLS",
+            "20 PRINT \"@home: 'x\"",
+            "30 IF INKEY$=\"\" THEN GOTO 20 ELSE GOSUB 50",
+            "40 ON X GOTO 20,50",
+            "50 BEEP",
+            "60 RETURN",
+            "70 REM @loop",
+        ]);
+
+        let err = preprocess(&source("GOTO @nowhere")).unwrap_err().to_string();
+        assert_eq!(err, "Source error: test:1: Undefined label @nowhere");
+        assert!(preprocess(&source("@a:\n@a:\nEND")).is_err());
+        assert!(preprocess(&source("END\n@a:")).is_err());
+
+        // 文字列の中のかなと、REM を含む変数名
+        let src = source("@top:\nPRINT \"あいう\"\nPREMIUM=1:GOTO @top\nPRINT 1:rem @top\nEND");
+        assert_eq!(preprocess(&src).unwrap(), [
+            "10 PRINT \"あいう\"",
+            "20 PREMIUM=1:GOTO 10",
+            "30 PRINT 1:rem @top",
+            "40 END",
+        ]);
+
+        assert!(is_source(&["' comment".to_string(), "CLS".to_string()]));
+        assert!(!is_source(&["".to_string(), "10 CLS".to_string()]));
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("msxterm_inc_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.src"), "GOSUB @init\nEND\n#include \"lib.src\"\n").unwrap();
+        std::fs::write(dir.join("lib.src"), "@init:\n  COLOR 15,1,1\n  RETURN\n").unwrap();
+        std::fs::write(dir.join("loop.src"), "#include \"loop.src\"\n").unwrap();
+        let lines = load_source(&dir.join("main.src")).unwrap();
+        let looped = load_source(&dir.join("loop.src"));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(lines, ["10 GOSUB 30", "20 END", "30 COLOR 15,1,1", "40 RETURN"]);
+        assert!(matches!(looped, Err(MsxError::Source(_))));
+    }
+}
@@ -0,0 +1,197 @@
+// Raw Keystroke Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// 行エディタを通さずにキー入力を一文字ずつ MSX に送る
+// INKEY$ や INPUT$(1) を使うプログラム用
+//
+use std::fmt;
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::terminal;
+use crate::error::{self, MsxError};
+
+/// MSX に送るキー
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RawKey {
+    /// 制御コード
+    Code(u8),
+    /// 文字 (漢字モードに合わせて変換して送る)
+    Char(char),
+}
+
+/// 行エディタに戻るためのキーの組み合わせ
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyChord {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl Default for KeyChord {
+    fn default() -> Self {
+        KeyChord { code: KeyCode::Char(']'), modifiers: KeyModifiers::CONTROL }
+    }
+}
+
+impl KeyChord {
+    // "ctrl-]" "alt-q" "f12" などの表記を解釈する
+    pub fn parse(text: &str) -> Result<KeyChord, String> {
+        let lower = text.trim().to_lowercase();
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = lower.as_str();
+        loop {
+            if let Some(r) = rest.strip_prefix("ctrl-") {
+                modifiers |= KeyModifiers::CONTROL;
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix("alt-") {
+                modifiers |= KeyModifiers::ALT;
+                rest = r;
+            } else {
+                break;
+            }
+        }
+        let mut chars = rest.chars();
+        let code = match (chars.next(), chars.next()) {
+            (Some(c), None) => KeyCode::Char(c),
+            _ => match rest {
+                "esc" => KeyCode::Esc,
+                "tab" => KeyCode::Tab,
+                _ => match rest.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                    Some(n) if (1..=12).contains(&n) => KeyCode::F(n),
+                    _ => return Err(format!("Bad key chord: {}", text)),
+                },
+            },
+        };
+        Ok(KeyChord { code, modifiers })
+    }
+
+    pub fn matches(&self, key: &KeyEvent) -> bool {
+        let (code, modifiers) = normalize(key);
+        code == self.code && modifiers == self.modifiers
+    }
+}
+
+impl fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            write!(f, "Ctrl-")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            write!(f, "Alt-")?;
+        }
+        match self.code {
+            KeyCode::Char(c) => write!(f, "{}", c.to_ascii_uppercase()),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            KeyCode::Esc => write!(f, "Esc"),
+            KeyCode::Tab => write!(f, "Tab"),
+            _ => write!(f, "{:?}", self.code),
+        }
+    }
+}
+
+// 比較用にキーを揃える
+// Ctrl-\ ～ Ctrl-_ は端末によって Ctrl-4 ～ Ctrl-7 として届く
+fn normalize(key: &KeyEvent) -> (KeyCode, KeyModifiers) {
+    let modifiers = key.modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT);
+    let code = match key.code {
+        KeyCode::Char(c) if modifiers.contains(KeyModifiers::CONTROL) => {
+            match c {
+                '4' => KeyCode::Char('\\'),
+                '5' => KeyCode::Char(']'),
+                '6' => KeyCode::Char('^'),
+                '7' => KeyCode::Char('_'),
+                _ => KeyCode::Char(c.to_ascii_lowercase()),
+            }
+        },
+        code => code,
+    };
+    (code, modifiers)
+}
+
+// PC のキーを MSX のキーコードに変換する
+pub fn key_to_msx(key: &KeyEvent) -> Option<RawKey> {
+    let (code, modifiers) = normalize(key);
+    let ctrl = modifiers.contains(KeyModifiers::CONTROL);
+    let raw = match code {
+        KeyCode::Right => RawKey::Code(0x1c),
+        KeyCode::Left => RawKey::Code(0x1d),
+        KeyCode::Up => RawKey::Code(0x1e),
+        KeyCode::Down => RawKey::Code(0x1f),
+        KeyCode::Home => RawKey::Code(0x0b),
+        KeyCode::Insert => RawKey::Code(0x12),
+        KeyCode::Delete => RawKey::Code(0x7f),
+        KeyCode::Backspace => RawKey::Code(0x08),
+        KeyCode::Tab => RawKey::Code(0x09),
+        KeyCode::Enter => RawKey::Code(0x0d),
+        KeyCode::Esc => RawKey::Code(0x1b),
+        // Ctrl-C は STOP (0x03) になる
+        KeyCode::Char(c) if ctrl && ('@'..='_').contains(&c.to_ascii_uppercase()) => {
+            RawKey::Code(c.to_ascii_uppercase() as u8 & 0x1f)
+        },
+        KeyCode::Char(c) if !ctrl => RawKey::Char(c),
+        _ => return None,
+    };
+    Some(raw)
+}
+
+//
+// ターミナルを raw モードにしてキー入力を送り続ける
+// escape のキーが押されたら元に戻る
+//
+pub fn run<F: FnMut(RawKey)>(escape: &KeyChord, mut send: F) -> error::Result<()> {
+    terminal::enable_raw_mode().map_err(MsxError::Terminal)?;
+    let result = loop {
+        let key = match event::read() {
+            Ok(Event::Key(key)) => key,
+            Ok(_) => continue,
+            Err(e) => break Err(MsxError::Terminal(e)),
+        };
+        // Windows では離した時のイベントも届く
+        if key.kind == KeyEventKind::Release {
+            continue;
+        }
+        if escape.matches(&key) {
+            break Ok(());
+        }
+        if let Some(raw) = key_to_msx(&key) {
+            send(raw);
+        }
+    };
+    let _ = terminal::disable_raw_mode();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn test_key_to_msx() {
+        assert_eq!(key_to_msx(&key(KeyCode::Right, KeyModifiers::NONE)), Some(RawKey::Code(0x1c)));
+        assert_eq!(key_to_msx(&key(KeyCode::Left, KeyModifiers::NONE)), Some(RawKey::Code(0x1d)));
+        assert_eq!(key_to_msx(&key(KeyCode::Up, KeyModifiers::NONE)), Some(RawKey::Code(0x1e)));
+        assert_eq!(key_to_msx(&key(KeyCode::Down, KeyModifiers::NONE)), Some(RawKey::Code(0x1f)));
+        assert_eq!(key_to_msx(&key(KeyCode::Char('c'), KeyModifiers::CONTROL)), Some(RawKey::Code(0x03)));
+        assert_eq!(key_to_msx(&key(KeyCode::Char('A'), KeyModifiers::SHIFT)), Some(RawKey::Char('A')));
+        assert_eq!(key_to_msx(&key(KeyCode::Enter, KeyModifiers::NONE)), Some(RawKey::Code(0x0d)));
+        assert_eq!(key_to_msx(&key(KeyCode::F(1), KeyModifiers::NONE)), None);
+    }
+
+    #[test]
+    fn test_key_chord() {
+        let chord = KeyChord::default();
+        assert!(chord.matches(&key(KeyCode::Char(']'), KeyModifiers::CONTROL)));
+        assert!(chord.matches(&key(KeyCode::Char('5'), KeyModifiers::CONTROL)));
+        assert!(!chord.matches(&key(KeyCode::Char(']'), KeyModifiers::NONE)));
+        assert_eq!(chord.to_string(), "Ctrl-]");
+
+        let chord = KeyChord::parse("Ctrl-X").unwrap();
+        assert!(chord.matches(&key(KeyCode::Char('x'), KeyModifiers::CONTROL)));
+        assert_eq!(KeyChord::parse("alt-f12").unwrap().to_string(), "Alt-F12");
+        assert!(KeyChord::parse("ctrl-foo").is_err());
+    }
+}
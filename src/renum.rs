@@ -0,0 +1,168 @@
+// MSX-BASIC Renumber Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// プログラムバッファの行番号を MSX の RENUM と同じように付け直す
+//
+use std::collections::{BTreeMap, HashMap};
+use crate::lexer::{self, Kind};
+
+// MSX で使える最大の行番号
+pub const MAX_LINE: u32 = 65529;
+
+/// RENUM の引数
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RenumParam {
+    pub new_start: u16,
+    /// None の場合はプログラムの先頭から
+    pub old_start: Option<u16>,
+    pub step: u16,
+}
+
+impl Default for RenumParam {
+    fn default() -> Self {
+        RenumParam { new_start: 10, old_start: None, step: 10 }
+    }
+}
+
+impl RenumParam {
+    // "100,10,10" ",,5" のような引数を解釈する (省略した項目は既定値)
+    pub fn parse(args: &str) -> Result<RenumParam, String> {
+        let mut param = RenumParam::default();
+        let args = args.trim();
+        if args.is_empty() {
+            return Ok(param);
+        }
+        let parts: Vec<&str> = args.split(',').map(|s| s.trim()).collect();
+        if parts.len() > 3 {
+            return Err(format!("Bad renum parameter: {}", args));
+        }
+        let num = |s: &str| s.parse::<u16>().map_err(|_| format!("Bad renum parameter: {}", s));
+        if let Some(s) = parts.first().filter(|s| !s.is_empty()) {
+            param.new_start = num(s)?;
+        }
+        if let Some(s) = parts.get(1).filter(|s| !s.is_empty()) {
+            param.old_start = Some(num(s)?);
+        }
+        if let Some(s) = parts.get(2).filter(|s| !s.is_empty()) {
+            param.step = num(s)?;
+        }
+        if param.step == 0 {
+            return Err("Bad renum parameter: step 0".to_string());
+        }
+        Ok(param)
+    }
+
+    // MSX に送る RENUM 命令
+    pub fn to_command(self) -> String {
+        match self.old_start {
+            Some(old) => format!("RENUM {},{},{}", self.new_start, old, self.step),
+            None => format!("RENUM {},,{}", self.new_start, self.step),
+        }
+    }
+}
+
+/// RENUM の結果
+pub struct Renumbered {
+    pub program: BTreeMap<u16, String>,
+    /// 飛び先のない参照 (参照している行の新しい番号, 参照先)
+    pub undefined: Vec<(u16, u16)>,
+}
+
+//
+// 行番号を付け直し、GOTO などの参照も書き換える
+// 飛び先のない参照はそのまま残して報告する
+//
+pub fn renum(program: &BTreeMap<u16, String>, param: RenumParam) -> Result<Renumbered, String> {
+    let old_start = param.old_start.unwrap_or(0);
+    // 付け直す前の行と重なったり、最大の行番号を超える場合は MSX と同じくエラー
+    if let Some((&last, _)) = program.range(..old_start).next_back() {
+        if param.new_start <= last {
+            return Err("Illegal function call".to_string());
+        }
+    }
+    let mut map = HashMap::new();
+    let mut next = param.new_start as u32;
+    for &num in program.range(old_start..).map(|(n, _)| n) {
+        if next > MAX_LINE {
+            return Err("Illegal function call".to_string());
+        }
+        map.insert(num, next as u16);
+        next += param.step as u32;
+    }
+
+    let mut renumbered = BTreeMap::new();
+    let mut undefined = Vec::new();
+    for (num, line) in program {
+        let new_num = *map.get(num).unwrap_or(num);
+        let mut text = String::with_capacity(line.len());
+        for lexeme in lexer::lex(line) {
+            if lexeme.kind != Kind::LineRef {
+                text.push_str(lexeme.text);
+                continue;
+            }
+            match lexeme.text.parse::<u16>() {
+                Ok(target) if map.contains_key(&target) => text.push_str(&map[&target].to_string()),
+                Ok(target) => {
+                    if !program.contains_key(&target) {
+                        undefined.push((new_num, target));
+                    }
+                    text.push_str(lexeme.text);
+                },
+                Err(_) => text.push_str(lexeme.text),
+            }
+        }
+        renumbered.insert(new_num, text);
+    }
+    Ok(Renumbered { program: renumbered, undefined })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program(lines: &[(u16, &str)]) -> BTreeMap<u16, String> {
+        lines.iter().map(|(n, l)| (*n, l.to_string())).collect()
+    }
+
+    #[test]
+    fn test_renum() {
+        let prog = program(&[
+            (5, "ON ERROR GOTO 60"),
+            (12, "ON X GOSUB 30,47:IF A THEN 5 ELSE 12"),
+            (30, "RESTORE 47:PRINT \"GOTO 30\":RETURN"),
+            (47, "DATA 12,30"),
+            (60, "IF ERL=12 THEN RESUME 30 ELSE RUN 99"),
+            (70, "LIST 12-47:DELETE 30-60 ' GOTO 12"),
+        ]);
+        let r = renum(&prog, RenumParam::default()).unwrap();
+        assert_eq!(r.program, program(&[
+            (10, "ON ERROR GOTO 50"),
+            (20, "ON X GOSUB 30,40:IF A THEN 10 ELSE 20"),
+            (30, "RESTORE 40:PRINT \"GOTO 30\":RETURN"),
+            (40, "DATA 12,30"),
+            (50, "IF ERL=20 THEN RESUME 30 ELSE RUN 99"),
+            (60, "LIST 20-40:DELETE 30-50 ' GOTO 12"),
+        ]));
+        assert_eq!(r.undefined, [(50, 99)]);
+
+        // 途中から付け直す
+        let r = renum(&prog, RenumParam::parse("100,30,5").unwrap()).unwrap();
+        assert_eq!(r.program.keys().copied().collect::<Vec<_>>(), [5, 12, 100, 105, 110, 115]);
+        assert_eq!(r.program[&12], "ON X GOSUB 100,105:IF A THEN 5 ELSE 12");
+
+        // 前の行と重なる
+        assert!(renum(&prog, RenumParam::parse("10,30").unwrap()).is_err());
+        assert!(renum(&prog, RenumParam::parse("65500,,10").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_renum_param() {
+        assert_eq!(RenumParam::parse("").unwrap(), RenumParam::default());
+        assert_eq!(RenumParam::parse(",,5").unwrap(), RenumParam { new_start: 10, old_start: None, step: 5 });
+        assert_eq!(RenumParam::parse("1000, 100").unwrap().to_command(), "RENUM 1000,100,10");
+        assert!(RenumParam::parse("10,20,0").is_err());
+        assert!(RenumParam::parse("a").is_err());
+    }
+}
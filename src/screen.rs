@@ -0,0 +1,476 @@
+// MSX Screen Control Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// MSX の画面制御コード (VT52 風のエスケープシーケンス) の解釈
+// ANSI / xterm への変換と、MSX のテキスト画面の再現に使う
+//
+use unicode_width::UnicodeWidthChar;
+
+const C_ESC: char = '\u{001b}';
+
+// 画面の行数
+pub const SCREEN_HEIGHT: usize = 24;
+
+// 全角文字の右半分のセル
+const WIDE_TAIL: char = '\0';
+
+// 端末で使う桁数 (全角文字は 2桁)
+fn char_width(c: char) -> usize {
+    c.width().unwrap_or(1).clamp(1, 2)
+}
+
+/// 画面制御の操作
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScreenOp {
+    Char(char),
+    Cr,
+    Lf,
+    Bs,
+    Tab,
+    Bell,
+    Home,
+    Cls,
+    Up,
+    Down,
+    Right,
+    Left,
+    EraseEol,
+    EraseEos,
+    EraseLine,
+    InsertLine,
+    DeleteLine,
+    Locate(usize, usize),
+    BlockCursor,
+    UnderlineCursor,
+    CursorHide,
+    CursorShow,
+}
+
+// エスケープシーケンスの解析状態
+// ESC Y r c のように読み込みの切れ目で分断されても続きから解釈する
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum EscState {
+    Normal,
+    Esc,
+    // ESC Y 行 桁
+    LocateRow,
+    LocateCol(usize),
+    // ESC x / ESC y カーソル形状
+    CursorOff,
+    CursorOn,
+}
+
+/// MSX の出力を ScreenOp に分解する
+pub struct Vt52Parser {
+    state: EscState,
+}
+
+impl Default for Vt52Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vt52Parser {
+    pub fn new() -> Vt52Parser {
+        Vt52Parser { state: EscState::Normal }
+    }
+
+    pub fn parse<F: FnMut(ScreenOp)>(&mut self, input: &str, mut f: F) {
+        for c in input.chars() {
+            self.state = match self.state {
+                EscState::Normal => {
+                    match c {
+                        C_ESC => EscState::Esc,
+                        _ => {
+                            f(ctrl_to_op(c));
+                            EscState::Normal
+                        },
+                    }
+                },
+                EscState::Esc => {
+                    match c {
+                        'Y' => EscState::LocateRow,
+                        'x' => EscState::CursorOff,
+                        'y' => EscState::CursorOn,
+                        _ => {
+                            // 未対応のシーケンスは捨てる
+                            if let Some(op) = esc_to_op(c) {
+                                f(op);
+                            }
+                            EscState::Normal
+                        },
+                    }
+                },
+                EscState::LocateRow => EscState::LocateCol(offset(c)),
+                EscState::LocateCol(row) => {
+                    f(ScreenOp::Locate(row, offset(c)));
+                    EscState::Normal
+                },
+                EscState::CursorOff => {
+                    match c {
+                        '4' => f(ScreenOp::BlockCursor),
+                        '5' => f(ScreenOp::CursorHide),
+                        _ => {},
+                    }
+                    EscState::Normal
+                },
+                EscState::CursorOn => {
+                    match c {
+                        '4' => f(ScreenOp::UnderlineCursor),
+                        '5' => f(ScreenOp::CursorShow),
+                        _ => {},
+                    }
+                    EscState::Normal
+                },
+            };
+        }
+    }
+}
+
+// 制御コード
+fn ctrl_to_op(c: char) -> ScreenOp {
+    match c {
+        '\u{0007}' => ScreenOp::Bell,
+        '\u{0008}' => ScreenOp::Bs,
+        '\u{0009}' => ScreenOp::Tab,
+        '\u{000a}' => ScreenOp::Lf,
+        '\u{000b}' => ScreenOp::Home,
+        '\u{000c}' => ScreenOp::Cls,
+        '\u{000d}' => ScreenOp::Cr,
+        '\u{001c}' => ScreenOp::Right,
+        '\u{001d}' => ScreenOp::Left,
+        '\u{001e}' => ScreenOp::Up,
+        '\u{001f}' => ScreenOp::Down,
+        _ => ScreenOp::Char(c),
+    }
+}
+
+// ESC + 一文字 のシーケンス
+fn esc_to_op(c: char) -> Option<ScreenOp> {
+    match c {
+        'A' => Some(ScreenOp::Up),
+        'B' => Some(ScreenOp::Down),
+        'C' => Some(ScreenOp::Right),
+        'D' => Some(ScreenOp::Left),
+        'H' => Some(ScreenOp::Home),
+        'E' | 'j' => Some(ScreenOp::Cls),
+        'K' => Some(ScreenOp::EraseEol),
+        'J' => Some(ScreenOp::EraseEos),
+        'l' => Some(ScreenOp::EraseLine),
+        'L' => Some(ScreenOp::InsertLine),
+        'M' => Some(ScreenOp::DeleteLine),
+        _ => None,
+    }
+}
+
+// ESC Y の座標は 0x20 からのオフセット
+fn offset(c: char) -> usize {
+    (c as usize).saturating_sub(0x20)
+}
+
+/// MSX の画面制御コードを ANSI / xterm に変換する
+#[derive(Default)]
+pub struct Vt52Translator {
+    parser: Vt52Parser,
+}
+
+impl Vt52Translator {
+    pub fn new() -> Vt52Translator {
+        Vt52Translator { parser: Vt52Parser::new() }
+    }
+
+    pub fn translate(&mut self, input: &str) -> String {
+        let mut out = String::with_capacity(input.len());
+        self.parser.parse(input, |op| {
+            match op {
+                ScreenOp::Char(c) => out.push(c),
+                ScreenOp::Cr => out.push('\r'),
+                ScreenOp::Lf => out.push('\n'),
+                ScreenOp::Bs => out.push('\u{0008}'),
+                ScreenOp::Tab => out.push('\t'),
+                ScreenOp::Bell => out.push('\u{0007}'),
+                ScreenOp::Home => out.push_str("\x1b[H"),
+                ScreenOp::Cls => out.push_str("\x1b[2J\x1b[H"),
+                ScreenOp::Up => out.push_str("\x1b[A"),
+                ScreenOp::Down => out.push_str("\x1b[B"),
+                ScreenOp::Right => out.push_str("\x1b[C"),
+                ScreenOp::Left => out.push_str("\x1b[D"),
+                ScreenOp::EraseEol => out.push_str("\x1b[K"),
+                ScreenOp::EraseEos => out.push_str("\x1b[J"),
+                ScreenOp::EraseLine => out.push_str("\x1b[2K"),
+                ScreenOp::InsertLine => out.push_str("\x1b[L"),
+                ScreenOp::DeleteLine => out.push_str("\x1b[M"),
+                ScreenOp::Locate(row, col) => out.push_str(&format!("\x1b[{};{}H", row + 1, col + 1)),
+                ScreenOp::BlockCursor => out.push_str("\x1b[2 q"),
+                ScreenOp::UnderlineCursor => out.push_str("\x1b[4 q"),
+                ScreenOp::CursorHide => out.push_str("\x1b[?25l"),
+                ScreenOp::CursorShow => out.push_str("\x1b[?25h"),
+            }
+        });
+        out
+    }
+}
+
+/// MSX のテキスト画面 (SCREEN 0/1) のモデル
+/// 受信した文字を書き込み、ホスト側のターミナルに画面全体を再描画する
+pub struct MsxScreen {
+    width: usize,
+    cells: Vec<Vec<char>>,
+    row: usize,
+    col: usize,
+    cursor_visible: bool,
+    parser: Vt52Parser,
+}
+
+impl MsxScreen {
+    pub fn new(width: usize) -> MsxScreen {
+        let width = width.clamp(1, 80);
+        MsxScreen {
+            width,
+            cells: vec![vec![' '; width]; SCREEN_HEIGHT],
+            row: 0,
+            col: 0,
+            cursor_visible: true,
+            parser: Vt52Parser::new(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    // WIDTH 命令と同じく画面をクリアする
+    pub fn set_width(&mut self, width: usize) {
+        *self = MsxScreen::new(width);
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+
+    // 各行の内容 (行末の空白は除く)
+    pub fn lines(&self) -> Vec<String> {
+        self.cells.iter()
+            .map(|row| row.iter().filter(|&&c| c != WIDE_TAIL).collect::<String>().trim_end().to_string())
+            .collect()
+    }
+
+    // msxcode でデコードした文字列を書き込む
+    pub fn feed(&mut self, input: &str) {
+        let mut parser = std::mem::take(&mut self.parser);
+        parser.parse(input, |op| self.apply(op));
+        self.parser = parser;
+    }
+
+    fn apply(&mut self, op: ScreenOp) {
+        match op {
+            ScreenOp::Char(c) => {
+                let w = char_width(c).min(self.width);
+                // 右端をまたぐ全角文字は次の行に書く
+                if self.col + w > self.width {
+                    self.col = 0;
+                    self.line_feed();
+                }
+                self.put(self.col, c);
+                if w == 2 {
+                    self.put(self.col + 1, WIDE_TAIL);
+                }
+                self.col += w;
+                if self.col >= self.width {
+                    self.col = 0;
+                    self.line_feed();
+                }
+            },
+            ScreenOp::Cr => self.col = 0,
+            ScreenOp::Lf | ScreenOp::Down => {
+                if op == ScreenOp::Down && self.row + 1 >= SCREEN_HEIGHT {
+                    return;
+                }
+                self.line_feed();
+            },
+            ScreenOp::Bs | ScreenOp::Left => {
+                if self.col > 0 {
+                    self.col -= 1;
+                } else if self.row > 0 {
+                    self.row -= 1;
+                    self.col = self.width - 1;
+                }
+                if op == ScreenOp::Bs {
+                    self.put(self.col, ' ');
+                }
+            },
+            ScreenOp::Right => {
+                self.col += 1;
+                if self.col >= self.width {
+                    self.col = 0;
+                    self.row = (self.row + 1).min(SCREEN_HEIGHT - 1);
+                }
+            },
+            ScreenOp::Up => self.row = self.row.saturating_sub(1),
+            ScreenOp::Tab => {
+                self.col = ((self.col / 8 + 1) * 8).min(self.width - 1);
+            },
+            ScreenOp::Bell => {},
+            ScreenOp::Home => {
+                self.row = 0;
+                self.col = 0;
+            },
+            ScreenOp::Cls => {
+                self.cells = vec![vec![' '; self.width]; SCREEN_HEIGHT];
+                self.row = 0;
+                self.col = 0;
+            },
+            ScreenOp::EraseEol => {
+                let col = self.col;
+                self.put(col, ' ');
+                self.cells[self.row][col..].fill(' ');
+            },
+            ScreenOp::EraseEos => {
+                let col = self.col;
+                self.put(col, ' ');
+                self.cells[self.row][col..].fill(' ');
+                for row in self.cells.iter_mut().skip(self.row + 1) {
+                    row.fill(' ');
+                }
+            },
+            ScreenOp::EraseLine => self.cells[self.row].fill(' '),
+            ScreenOp::InsertLine => {
+                self.cells.insert(self.row, vec![' '; self.width]);
+                self.cells.truncate(SCREEN_HEIGHT);
+            },
+            ScreenOp::DeleteLine => {
+                self.cells.remove(self.row);
+                self.cells.push(vec![' '; self.width]);
+            },
+            ScreenOp::Locate(row, col) => {
+                self.row = row.min(SCREEN_HEIGHT - 1);
+                self.col = col.min(self.width - 1);
+            },
+            ScreenOp::CursorHide => self.cursor_visible = false,
+            ScreenOp::CursorShow => self.cursor_visible = true,
+            ScreenOp::BlockCursor | ScreenOp::UnderlineCursor => {},
+        }
+    }
+
+    // カーソル行の col 桁に書く
+    // 全角文字の片側だけを書き換える場合は、残った側を空白にする
+    fn put(&mut self, col: usize, c: char) {
+        let row = &mut self.cells[self.row];
+        if c != WIDE_TAIL && row[col] == WIDE_TAIL && col > 0 {
+            row[col - 1] = ' ';
+        }
+        if col + 1 < row.len() && row[col + 1] == WIDE_TAIL && char_width(row[col]) == 2 {
+            row[col + 1] = ' ';
+        }
+        row[col] = c;
+    }
+
+    // 改行 最下行ではスクロールする
+    fn line_feed(&mut self) {
+        if self.row + 1 >= SCREEN_HEIGHT {
+            self.cells.remove(0);
+            self.cells.push(vec![' '; self.width]);
+        } else {
+            self.row += 1;
+        }
+    }
+
+    // ミラー表示の開始
+    // 画面上部に MSX 画面を描き、その下をスクロール領域にしてプロンプトを置く
+    pub fn enter_mirror(&self) -> String {
+        let mut out = String::from("\x1b[2J");
+        out.push_str(&self.render());
+        out.push_str(&format!("\x1b[{}r\x1b[999;1H", SCREEN_HEIGHT + 3));
+        out
+    }
+
+    // ミラー表示の終了
+    pub fn leave_mirror() -> String {
+        "\x1b[r\x1b[2J\x1b[H".to_string()
+    }
+
+    // 画面全体を再描画する文字列 (カーソル位置は保存・復帰する)
+    // 全角文字は 2セル分使うので右半分のセルは出力しない
+    pub fn render(&self) -> String {
+        let mut out = String::from("\x1b7");
+        let border = "─".repeat(self.width);
+        out.push_str(&format!("\x1b[1;1H┌{}┐\x1b[K", border));
+        for (i, row) in self.cells.iter().enumerate() {
+            out.push_str(&format!("\x1b[{};1H│", i + 2));
+            for (j, c) in row.iter().enumerate() {
+                if *c == WIDE_TAIL {
+                    continue;
+                }
+                if self.cursor_visible && (i, j) == (self.row, self.col) {
+                    out.push_str(&format!("\x1b[7m{}\x1b[0m", c));
+                } else {
+                    out.push(*c);
+                }
+            }
+            out.push_str("│\x1b[K");
+        }
+        out.push_str(&format!("\x1b[{};1H└{}┘\x1b[K", SCREEN_HEIGHT + 2, border));
+        out.push_str("\x1b8");
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate() {
+        let mut tr = Vt52Translator::new();
+        assert_eq!(tr.translate("\x0cA\x1bY%("), "\x1b[2J\x1b[HA\x1b[6;9H");
+        assert_eq!(tr.translate("\x1c\x1d\x1e\x1f\x0b"), "\x1b[C\x1b[D\x1b[A\x1b[B\x1b[H");
+        assert_eq!(tr.translate("\x1bK\x1bJ\x1bx5\x1by5Ok\r\n"), "\x1b[K\x1b[J\x1b[?25l\x1b[?25hOk\r\n");
+
+        // 分断されたシーケンス
+        assert_eq!(tr.translate("AB\x1bY"), "AB");
+        assert_eq!(tr.translate(" "), "");
+        assert_eq!(tr.translate("!C"), "\x1b[1;2HC");
+    }
+
+    #[test]
+    fn test_screen() {
+        let mut sc = MsxScreen::new(32);
+        sc.feed("\x0cHELLO\r\nOk\r\n");
+        assert_eq!(sc.lines()[0..3], ["HELLO", "Ok", ""]);
+        assert_eq!(sc.cursor(), (2, 0));
+
+        // LOCATE 10,5:PRINT "*" 相当
+        sc.feed("\x1bY%**\x1bA*\x1bK");
+        assert_eq!(sc.lines()[4], "           *");
+        assert_eq!(sc.lines()[5], "          *");
+        assert_eq!(sc.cursor(), (4, 12));
+
+        // 行末での折り返しとスクロール
+        sc.feed("\x0c");
+        for i in 0..SCREEN_HEIGHT {
+            sc.feed(&format!("{}\r\n", i));
+        }
+        assert_eq!(sc.lines()[0], "1");
+        sc.feed(&"X".repeat(33));
+        assert_eq!(sc.lines()[SCREEN_HEIGHT - 2], "X".repeat(32));
+        assert_eq!(sc.lines()[SCREEN_HEIGHT - 1], "X");
+
+        // 全角文字は 2桁使い、右端を越える分は次の行に折り返す
+        let mut sc = MsxScreen::new(5);
+        sc.feed("\x0c漢字AB\r\n");
+        assert_eq!(sc.lines()[0..3], ["漢字A", "B", ""]);
+        assert_eq!(sc.cursor(), (2, 0));
+        let render = sc.render();
+        assert!(render.contains("\x1b[2;1H│漢字A│"));
+        assert!(render.contains("\x1b[3;1H│B    │"));
+        sc.feed("ABCD漢\r\n");
+        assert_eq!(sc.lines()[2..4], ["ABCD", "漢"]);
+
+        // 全角文字の半分を上書きすると残りは空白になる
+        sc.feed("\x0c漢字\x1bY !X");
+        assert_eq!(sc.lines()[0], " X字");
+    }
+}
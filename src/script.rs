@@ -0,0 +1,307 @@
+// Command Script Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// ターミナルの入力行を並べたスクリプト (.msxs) を実行する
+//
+//   ; コメント
+//   load prog.bas                 #load と同じ
+//   send "RUN"                    MSX に一行送る
+//   expect "Ok" timeout 30        受信した行を正規表現で待つ
+//   expect "SCORE (?P<score>\d+)" 名前付きグループは変数になる
+//   if-match "error" fail "NG"    直前の expect 以降に受信した行を調べる
+//   echo "score=${score}"
+//   sleep 0.5
+//   save result.bas
+//   #reload_to                    確認せずに送る (-y を付けたのと同じ)
+//   それ以外の行                   入力した行と同じように実行する
+//
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+use regex::Regex;
+use crate::error::{self, MsxError};
+use crate::helper::MsxEditor;
+use crate::{exec_line, transfer, Link, Msxterm};
+
+// expect の既定の待ち時間
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// 秒数の引数 (0 以上の有限の数)
+fn seconds(s: &str) -> error::Result<Duration> {
+    s.parse::<f64>().ok()
+        .and_then(|sec| Duration::try_from_secs_f64(sec).ok())
+        .ok_or(MsxError::Command(format!("Bad seconds: {}", s)))
+}
+
+// 引数を空白で区切る ("..." は一つの引数、\" と \\ はエスケープ)
+pub fn split_args(s: &str) -> error::Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = s.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c == ' ' || c == '\t' {
+            chars.next();
+            continue;
+        }
+        let mut arg = String::new();
+        if c == '"' {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(e @ ('"' | '\\')) => arg.push(e),
+                        Some(e) => {
+                            arg.push('\\');
+                            arg.push(e);
+                        },
+                        None => arg.push('\\'),
+                    },
+                    Some(ch) => arg.push(ch),
+                    None => return Err(MsxError::Command(format!("Unterminated string: {}", s.trim()))),
+                }
+            }
+        } else {
+            while let Some(&ch) = chars.peek() {
+                if ch == ' ' || ch == '\t' {
+                    break;
+                }
+                arg.push(ch);
+                chars.next();
+            }
+        }
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+// ${名前} を変数の値に置き換える (ない変数は空文字列)
+pub fn interpolate(s: &str, vars: &HashMap<String, String>) -> String {
+    let re = Regex::new(r"\$\{(\w+)\}").unwrap();
+    re.replace_all(s, |caps: &regex::Captures| vars.get(&caps[1]).cloned().unwrap_or_default())
+        .to_string()
+}
+
+pub struct Script<'a> {
+    msxterm: &'a mut Msxterm,
+    rl: &'a mut MsxEditor,
+    link: &'a Link,
+    rx: Receiver<String>,
+    vars: HashMap<String, String>,
+    // 直前の expect 以降に受信した行
+    received: Vec<String>,
+}
+
+impl<'a> Script<'a> {
+    pub fn new(msxterm: &'a mut Msxterm, rl: &'a mut MsxEditor, link: &'a Link) -> error::Result<Script<'a>> {
+        let rx = link.watch()?;
+        Ok(Script { msxterm, rl, link, rx, vars: HashMap::new(), received: Vec::new() })
+    }
+
+    pub fn var(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(|s| s.as_str())
+    }
+
+    // 受信済みの行を取り込む
+    fn drain(&mut self) {
+        while let Ok(line) = self.rx.try_recv() {
+            self.received.push(line);
+        }
+    }
+
+    // 名前付きグループを変数にする
+    fn capture(&mut self, re: &Regex, line: &str) -> bool {
+        let Some(caps) = re.captures(line) else {
+            return false;
+        };
+        for name in re.capture_names().flatten() {
+            if let Some(m) = caps.name(name) {
+                self.vars.insert(name.to_string(), m.as_str().to_string());
+            }
+        }
+        true
+    }
+
+    fn expect(&mut self, pattern: &str, timeout: Duration) -> error::Result<()> {
+        let re = Regex::new(pattern).map_err(|e| MsxError::Command(e.to_string()))?;
+        self.received.clear();
+        let deadline = Instant::now().checked_add(timeout)
+            .ok_or(MsxError::Command(format!("Timeout too long: {:?}", timeout)))?;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let line = transfer::recv_line(&self.rx, remaining, &format!("\"{}\"", pattern))?;
+            self.received.push(line.clone());
+            if self.capture(&re, &line) {
+                return Ok(());
+            }
+        }
+    }
+
+    //
+    // 一行実行する
+    // 失敗したらスクリプトを止める
+    //
+    pub fn exec(&mut self, line: &str) -> error::Result<()> {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with(';') {
+            return Ok(());
+        }
+        let (command, rest) = trimmed.split_once(' ').unwrap_or((trimmed, ""));
+        let args = || -> error::Result<Vec<String>> {
+            Ok(split_args(rest)?.iter().map(|a| interpolate(a, &self.vars)).collect())
+        };
+        match command {
+            "send" => {
+                let text = args()?.join(" ");
+                self.terminal_line(&text, false)
+            },
+            "expect" => {
+                let args = args()?;
+                let timeout = match args.as_slice() {
+                    [_] => EXPECT_TIMEOUT,
+                    [_, t, sec] if t == "timeout" => seconds(sec)?,
+                    _ => return Err(MsxError::Command("Usage: expect \"pattern\" [timeout sec]".to_string())),
+                };
+                self.expect(&args[0], timeout)
+            },
+            "if-match" => {
+                let (pattern, then) = match split_args(rest)?.first() {
+                    Some(p) => (interpolate(p, &self.vars), rest.trim_start()),
+                    None => return Err(MsxError::Command("Usage: if-match \"pattern\" command".to_string())),
+                };
+                // 条件の後ろの命令 (最初の引数を飛ばす)
+                let then = match then.strip_prefix('"') {
+                    Some(q) => skip_quoted(q),
+                    None => then.split_once(' ').map_or("", |(_, t)| t),
+                };
+                let re = Regex::new(&pattern).map_err(|e| MsxError::Command(e.to_string()))?;
+                self.drain();
+                let lines = self.received.clone();
+                if lines.iter().any(|l| self.capture(&re, l)) {
+                    self.exec(then)?;
+                }
+                Ok(())
+            },
+            "sleep" => {
+                let sec = match args()?.first() {
+                    Some(s) => seconds(s)?,
+                    None => return Err(MsxError::Command("Usage: sleep sec".to_string())),
+                };
+                std::thread::sleep(sec);
+                Ok(())
+            },
+            "load" | "save" => {
+                let line = format!("#{} {}", command, args()?.join(" "));
+                self.terminal_line(&line, true)
+            },
+            "echo" => {
+                println!("{}", args()?.join(" "));
+                Ok(())
+            },
+            "fail" => Err(MsxError::Command(args()?.join(" "))),
+            _ => self.terminal_line(&interpolate(trimmed, &self.vars), true),
+        }
+    }
+
+    // 入力した行と同じように実行する
+    // terminal でなければ MSX に送る行だけ受け付ける (send)
+    fn terminal_line(&mut self, line: &str, terminal: bool) -> error::Result<()> {
+        let is_command = terminal && line.starts_with('#');
+        if !is_command && line.starts_with('#') {
+            return Err(MsxError::Command(format!("Cannot send a terminal command: {}", line)));
+        }
+        // スクリプトからは確認の入力ができないので確認しない
+        let line = if is_command && line.split_whitespace().next() == Some("#reload_to") {
+            format!("{} -y", line)
+        } else {
+            line.to_string()
+        };
+        if !exec_line(&line, self.msxterm, self.rl, self.link)? {
+            return Err(MsxError::Command("Quit".to_string()));
+        }
+        // #load などは受信した行を横取りするので受け取り直す
+        if is_command {
+            self.rx = self.link.watch()?;
+        }
+        Ok(())
+    }
+}
+
+// "..." の閉じる " の後ろ
+fn skip_quoted(s: &str) -> &str {
+    let mut escaped = false;
+    for (n, c) in s.char_indices() {
+        match c {
+            '\\' if !escaped => escaped = true,
+            '"' if !escaped => return s[n + 1..].trim_start(),
+            _ => escaped = false,
+        }
+    }
+    ""
+}
+
+impl Drop for Script<'_> {
+    fn drop(&mut self) {
+        self.link.unwatch();
+    }
+}
+
+//
+// スクリプトファイルを実行する
+// エラーの場合はファイル名と行番号を付ける
+//
+pub fn run_file(path: &Path, msxterm: &mut Msxterm, rl: &mut MsxEditor, link: &Link) -> error::Result<()> {
+    let text = std::fs::read_to_string(path).map_err(|e| MsxError::File(path.display().to_string(), e))?;
+    let mut script = Script::new(msxterm, rl, link)?;
+    for (n, line) in text.lines().enumerate() {
+        script.exec(line)
+            .map_err(|e| MsxError::Script(format!("{}:{}: {}", path.display(), n + 1, e)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::tests::sim_link;
+
+    #[test]
+    fn test_split_args() {
+        assert_eq!(split_args(r#"expect "A \"B\"" timeout 5"#).unwrap(), ["expect", "A \"B\"", "timeout", "5"]);
+        assert_eq!(split_args(r#""\d+" x"#).unwrap(), ["\\d+", "x"]);
+        assert!(split_args("\"abc").is_err());
+        let vars = HashMap::from([("n".to_string(), "42".to_string())]);
+        assert_eq!(interpolate("N=${n}${none}", &vars), "N=42");
+        assert_eq!(skip_quoted(r#"a\"b" fail "x""#), "fail \"x\"");
+    }
+
+    #[test]
+    fn test_script() {
+        let link = sim_link();
+        let mut mt = Msxterm::new();
+        let mut rl = MsxEditor::new().unwrap();
+        let mut script = Script::new(&mut mt, &mut rl, &link).unwrap();
+        script.exec("; comment").unwrap();
+        script.exec("10 PRINT \"SCORE 120\"").unwrap();
+        script.exec("send RUN").unwrap();
+        script.exec(r#"expect "SCORE (?P<score>\d+)" timeout 5"#).unwrap();
+        assert_eq!(script.var("score"), Some("120"));
+        script.exec(r#"expect "^Ok""#).unwrap();
+        script.exec(r#"if-match "SCORE" fail "score ${score}""#).unwrap_err();
+        script.exec(r#"if-match "error" fail "error""#).unwrap();
+        script.exec("send FOO").unwrap();
+        script.exec(r#"expect "^Ok" timeout 5"#).unwrap();
+        let err = script.exec(r#"if-match "(?P<msg>.* error)" fail "${msg}""#).unwrap_err();
+        assert_eq!(err.to_string(), "Command error: Syntax error");
+        assert!(matches!(script.exec(r#"expect "never" timeout 0.3"#), Err(MsxError::Timeout(_))));
+        assert!(script.exec("send #list").is_err());
+        // 確認の入力を待たない
+        script.exec("#reload_to").unwrap();
+        assert!(script.exec("sleep -1").is_err());
+        assert!(script.exec("sleep inf").is_err());
+        assert!(script.exec(r#"expect "Ok" timeout -1"#).is_err());
+        assert!(script.exec(r#"expect "Ok" timeout 1e300"#).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+// Session Log Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// MSX からの出力をファイルに記録する
+//   <file>      デコードした文字列 (行頭に経過時間を付けることもできる)
+//   <file>.raw  送受信したバイト列そのもの (--raw の場合)
+//               "経過秒 TX|RX 16進数..." の形で一回の送受信を一行にする
+//
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use crate::error::{self, MsxError};
+
+/// 送受信の向き
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+impl Direction {
+    pub fn tag(self) -> &'static str {
+        match self {
+            Direction::Tx => "TX",
+            Direction::Rx => "RX",
+        }
+    }
+}
+
+pub struct SessionLog {
+    path: PathBuf,
+    text: BufWriter<File>,
+    raw: Option<BufWriter<File>>,
+    timestamp: bool,
+    start: Instant,
+    // 次の出力が行頭か
+    line_start: bool,
+}
+
+// UNIX 時間を "YYYY-MM-DD hh:mm:ss UTC" にする
+fn format_utc(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rest = secs % 86400;
+    // 1970-01-01 からの日数を年月日にする (civil_from_days)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year, month, day, rest / 3600, rest / 60 % 60, rest % 60)
+}
+
+// 生データのファイル名
+pub fn raw_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".raw");
+    PathBuf::from(name)
+}
+
+impl SessionLog {
+    pub fn open(path: &Path, timestamp: bool, raw: bool) -> error::Result<SessionLog> {
+        let create = |p: &Path| File::create(p)
+            .map(BufWriter::new)
+            .map_err(|e| MsxError::File(p.display().to_string(), e));
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let header = format!("# msxterm log started {}\n", format_utc(now));
+        let mut text = create(path)?;
+        let mut raw = if raw { Some(create(&raw_path(path))?) } else { None };
+        let write_err = |e| MsxError::File(path.display().to_string(), e);
+        text.write_all(header.as_bytes()).map_err(write_err)?;
+        if let Some(ref mut r) = raw {
+            r.write_all(header.as_bytes()).map_err(write_err)?;
+        }
+        Ok(SessionLog {
+            path: path.to_path_buf(),
+            text,
+            raw,
+            timestamp,
+            start: Instant::now(),
+            line_start: true,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_raw(&self) -> bool {
+        self.raw.is_some()
+    }
+
+    fn elapsed(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+
+    // デコードした出力
+    // 書き込みに失敗しても通信は続けたいので、エラーは無視する
+    pub fn output(&mut self, text: &str) {
+        if !self.timestamp {
+            let _ = self.text.write_all(text.as_bytes());
+            return;
+        }
+        let stamp = format!("[{:9.3}] ", self.elapsed());
+        for piece in text.split_inclusive('\n') {
+            if self.line_start {
+                let _ = self.text.write_all(stamp.as_bytes());
+            }
+            let _ = self.text.write_all(piece.as_bytes());
+            self.line_start = piece.ends_with('\n');
+        }
+    }
+
+    // 送受信したバイト列
+    pub fn bytes(&mut self, dir: Direction, data: &[u8]) {
+        let elapsed = self.elapsed();
+        if let Some(ref mut raw) = self.raw {
+            let hex: Vec<String> = data.iter().map(|b| format!("{:02X}", b)).collect();
+            let _ = writeln!(raw, "{:.3} {} {}", elapsed, dir.tag(), hex.join(" "));
+        }
+    }
+
+    // 切断などの出来事
+    pub fn note(&mut self, message: &str) {
+        let line = format!("{}# {}\n", if self.line_start { "" } else { "\n" }, message);
+        let _ = self.text.write_all(line.as_bytes());
+        self.line_start = true;
+        if let Some(ref mut raw) = self.raw {
+            let _ = raw.write_all(format!("# {}\n", message).as_bytes());
+        }
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.text.flush();
+        if let Some(ref mut raw) = self.raw {
+            let _ = raw.flush();
+        }
+    }
+}
+
+impl Drop for SessionLog {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_log() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_utc(1_698_537_600 + 3661), "2023-10-29 01:01:01 UTC");
+
+        let path = std::env::temp_dir().join(format!("msxterm_log_{}.txt", std::process::id()));
+        {
+            let mut log = SessionLog::open(&path, true, true).unwrap();
+            log.bytes(Direction::Tx, b"LIST\r");
+            log.output("LIST\r\n10 PRI");
+            log.output("NT 1\r\n");
+            log.note("Disconnect");
+        }
+        let text = std::fs::read_to_string(&path).unwrap();
+        let raw = std::fs::read_to_string(raw_path(&path)).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(raw_path(&path));
+
+        let lines: Vec<&str> = text.lines().collect();
+        assert!(lines[0].starts_with("# msxterm log started"));
+        assert!(lines[1].starts_with("[    0.") && lines[1].ends_with("] LIST"));
+        assert!(lines[2].ends_with("] 10 PRINT 1"));
+        assert_eq!(lines[3], "# Disconnect");
+        let raw_lines: Vec<&str> = raw.lines().collect();
+        assert!(raw_lines[1].ends_with(" TX 4C 49 53 54 0D"));
+    }
+}
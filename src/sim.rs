@@ -0,0 +1,356 @@
+// MSX0 Simulator Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// 実機なしでテストするための MSX0 もどきの TCP サーバ
+// 受信した文字をエコーし、CR で一行として処理する。
+// 行番号付きの行はプログラムとして保持し、LIST / NEW / RUN などに応答する。
+//
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const U_BREAK:u8 = 0x03;
+const U_LF:u8 = 0x0a;
+const U_CR:u8 = 0x0d;
+
+// RUN で無限ループとみなすまでの実行ステップ数
+const MAX_STEPS: usize = 10_000;
+
+type Program = Arc<Mutex<BTreeMap<u16, Vec<u8>>>>;
+
+pub struct Simulator {
+    listener: TcpListener,
+    program: Program,
+}
+
+impl Simulator {
+    // "127.0.0.1:0" を指定すると空いているポートを使う
+    pub fn bind(addr: &str) -> std::io::Result<Simulator> {
+        Ok(Simulator {
+            listener: TcpListener::bind(addr)?,
+            program: Arc::new(Mutex::new(BTreeMap::new())),
+        })
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // 接続を待ち受ける (プログラムは接続をまたいで保持する)
+    pub fn run(self) {
+        for stream in self.listener.incoming().flatten() {
+            let program = Arc::clone(&self.program);
+            thread::spawn(move || {
+                let _ = Session::new(program).serve(stream);
+            });
+        }
+    }
+
+    // 別スレッドで待ち受けを開始して接続先アドレスを返す
+    pub fn spawn(self) -> std::io::Result<SocketAddr> {
+        let addr = self.local_addr()?;
+        thread::spawn(move || self.run());
+        Ok(addr)
+    }
+}
+
+struct Session {
+    program: Program,
+    input: Vec<u8>,
+    // 無限ループ中の行番号 (Break 待ち)
+    running: Option<u16>,
+}
+
+impl Session {
+    fn new(program: Program) -> Session {
+        Session { program, input: Vec::new(), running: None }
+    }
+
+    fn serve(&mut self, mut stream: TcpStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_millis(100)))?;
+        let mut buf = [0_u8; 256];
+        loop {
+            let size = match stream.read(&mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(size) => size,
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue,
+                Err(e) => return Err(e),
+            };
+            let mut out = Vec::new();
+            for &u in &buf[..size] {
+                self.receive(u, &mut out);
+            }
+            stream.write_all(&out)?;
+        }
+    }
+
+    // 一文字受信
+    fn receive(&mut self, u: u8, out: &mut Vec<u8>) {
+        match u {
+            U_BREAK => {
+                if let Some(num) = self.running.take() {
+                    out.extend_from_slice(format!("Break in {}\r\nOk\r\n", num).as_bytes());
+                } else {
+                    self.input.clear();
+                    out.extend_from_slice(b"\r\n");
+                }
+            },
+            U_LF => {},
+            U_CR => {
+                let line = std::mem::take(&mut self.input);
+                out.extend_from_slice(&line);
+                out.extend_from_slice(b"\r\n");
+                if self.running.is_none() {
+                    self.execute(&line, out);
+                }
+            },
+            _ => self.input.push(u),
+        }
+    }
+
+    // 一行実行
+    fn execute(&mut self, line: &[u8], out: &mut Vec<u8>) {
+        let line = trim(line);
+        if line.is_empty() {
+            return;
+        }
+        if line[0].is_ascii_digit() {
+            let digits = line.iter().take_while(|u| u.is_ascii_digit()).count();
+            let number = std::str::from_utf8(&line[..digits]).ok().and_then(|n| n.parse::<u16>().ok());
+            let Some(number) = number else {
+                out.extend_from_slice(b"Syntax error\r\nOk\r\n");
+                return;
+            };
+            let body = trim(&line[digits..]);
+            let mut program = self.program.lock().unwrap();
+            if body.is_empty() {
+                if program.remove(&number).is_none() {
+                    out.extend_from_slice(b"Undefined line number\r\nOk\r\n");
+                }
+            } else {
+                program.insert(number, body.to_vec());
+            }
+            return;
+        }
+
+        let upper = line.to_ascii_uppercase();
+        if upper.starts_with(b"LIST") {
+            let (start, end) = parse_range(&line[4..]);
+            let program = self.program.lock().unwrap();
+            for (num, body) in program.range(start..=end) {
+                out.extend_from_slice(format!("{} ", num).as_bytes());
+                out.extend_from_slice(body);
+                out.extend_from_slice(b"\r\n");
+            }
+        } else if upper.starts_with(b"DELETE") {
+            let (start, end) = parse_range(&line[6..]);
+            self.program.lock().unwrap().retain(|num, _| !(start..=end).contains(num));
+        } else if upper.starts_with(b"NEW") {
+            self.program.lock().unwrap().clear();
+        } else if upper.starts_with(b"RUN") {
+            self.run_program(out);
+            if self.running.is_some() {
+                return;
+            }
+        } else if upper.starts_with(b"PRINT") || upper.starts_with(b"?") {
+            if let Err(e) = statement(line, out) {
+                out.extend_from_slice(format!("{}\r\n", e).as_bytes());
+            }
+        } else if !is_command(&upper) {
+            out.extend_from_slice(b"Syntax error\r\n");
+        }
+        out.extend_from_slice(b"Ok\r\n");
+    }
+
+    // プログラム実行
+    // PRINT / GOTO / END / STOP / ERROR / REM のみ解釈し、他の命令は読み飛ばす
+    fn run_program(&mut self, out: &mut Vec<u8>) {
+        let program = self.program.lock().unwrap().clone();
+        let mut pc = program.keys().next().copied();
+        let mut steps = 0;
+        while let Some(num) = pc {
+            steps += 1;
+            if steps > MAX_STEPS {
+                self.running = Some(num);
+                return;
+            }
+            let mut next = program.range(num + 1..).next().map(|(n, _)| *n);
+            for stmt in split_statements(&program[&num]) {
+                match statement(stmt, out) {
+                    Ok(Flow::Next) => {},
+                    Ok(Flow::Goto(target)) => {
+                        if !program.contains_key(&target) {
+                            out.extend_from_slice(format!("Undefined line number in {}\r\n", num).as_bytes());
+                            return;
+                        }
+                        next = Some(target);
+                        break;
+                    },
+                    Ok(Flow::End) => return,
+                    Err(e) => {
+                        out.extend_from_slice(format!("{} in {}\r\n", e, num).as_bytes());
+                        return;
+                    }
+                }
+            }
+            pc = next;
+        }
+    }
+}
+
+enum Flow {
+    Next,
+    Goto(u16),
+    End,
+}
+
+// 一文実行
+fn statement(stmt: &[u8], out: &mut Vec<u8>) -> Result<Flow, String> {
+    let stmt = trim(stmt);
+    let upper = stmt.to_ascii_uppercase();
+    if let Some(args) = strip_keyword(&upper, stmt, b"PRINT").or_else(|| strip_keyword(&upper, stmt, b"?")) {
+        print(args, out)?;
+    } else if let Some(args) = strip_keyword(&upper, stmt, b"GOTO") {
+        let target = std::str::from_utf8(trim(args)).ok()
+            .and_then(|n| n.parse::<u16>().ok())
+            .ok_or("Syntax error")?;
+        return Ok(Flow::Goto(target));
+    } else if upper.starts_with(b"END") {
+        return Ok(Flow::End);
+    } else if upper.starts_with(b"STOP") {
+        return Err("Break".to_string());
+    } else if let Some(args) = strip_keyword(&upper, stmt, b"ERROR") {
+        let code = std::str::from_utf8(trim(args)).ok().and_then(|n| n.parse::<u8>().ok());
+        return Err(match code {
+            Some(2) => "Syntax error",
+            Some(8) => "Undefined line number",
+            Some(11) => "Division by zero",
+            Some(13) => "Type mismatch",
+            _ => "Unprintable error",
+        }.to_string());
+    }
+    Ok(Flow::Next)
+}
+
+// PRINT 文の引数を出力 (文字列と整数のみ)
+fn print(args: &[u8], out: &mut Vec<u8>) -> Result<(), String> {
+    let mut rest = trim(args);
+    let mut newline = true;
+    while !rest.is_empty() {
+        newline = true;
+        if rest[0] == b'"' {
+            let end = rest[1..].iter().position(|&u| u == b'"').map(|p| p + 1).unwrap_or(rest.len());
+            out.extend_from_slice(&rest[1..end]);
+            rest = if end < rest.len() { &rest[end + 1..] } else { &[] };
+        } else {
+            let len = rest.iter().position(|&u| u == b';' || u == b',').unwrap_or(rest.len());
+            let value = std::str::from_utf8(trim(&rest[..len])).ok()
+                .and_then(|n| n.parse::<i32>().ok())
+                .ok_or("Type mismatch")?;
+            // MSX は数値の前に符号分の空白、後ろに空白を出力する
+            let sign = if value < 0 { "" } else { " " };
+            out.extend_from_slice(format!("{}{} ", sign, value).as_bytes());
+            rest = &rest[len..];
+        }
+        rest = trim(rest);
+        if let Some(r) = rest.strip_prefix(b";").or_else(|| rest.strip_prefix(b",")) {
+            newline = false;
+            rest = trim(r);
+        }
+    }
+    if newline {
+        out.extend_from_slice(b"\r\n");
+    }
+    Ok(())
+}
+
+// 先頭のキーワードを取り除いた残りを返す
+fn strip_keyword<'a>(upper: &[u8], stmt: &'a [u8], keyword: &[u8]) -> Option<&'a [u8]> {
+    if upper.starts_with(keyword) {
+        Some(&stmt[keyword.len()..])
+    } else {
+        None
+    }
+}
+
+// 何もせず Ok を返すダイレクトコマンド
+fn is_command(upper: &[u8]) -> bool {
+    const COMMANDS: [&[u8]; 9] = [b"CLS", b"CALL", b"_", b"SCREEN", b"WIDTH", b"COLOR", b"KEY", b"REM", b"'"];
+    COMMANDS.iter().any(|c| upper.starts_with(c))
+}
+
+// ':' で文を分割する (文字列と REM 以降は分割しない)
+fn split_statements(line: &[u8]) -> Vec<&[u8]> {
+    let mut stmts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, &u) in line.iter().enumerate() {
+        if u == b'"' {
+            quoted = !quoted;
+        } else if !quoted && u == b'\'' {
+            break;
+        } else if !quoted && u == b':' {
+            stmts.push(&line[start..i]);
+            start = i + 1;
+        }
+        if !quoted && trim(&line[start..=i]).to_ascii_uppercase().starts_with(b"REM") {
+            break;
+        }
+    }
+    stmts.push(&line[start..]);
+    stmts
+}
+
+// "10-20" 形式の範囲
+fn parse_range(args: &[u8]) -> (u16, u16) {
+    let args = String::from_utf8_lossy(trim(args)).to_string();
+    let mut parts = args.split('-');
+    let start = parts.next().and_then(|x| x.trim().parse().ok());
+    match parts.next() {
+        Some(end) => (start.unwrap_or(0), end.trim().parse().unwrap_or(65535)),
+        None => (start.unwrap_or(0), start.unwrap_or(65535)),
+    }
+}
+
+fn trim(u: &[u8]) -> &[u8] {
+    let start = u.iter().position(|c| *c != b' ').unwrap_or(u.len());
+    let end = u.iter().rposition(|c| *c != b' ').map(|p| p + 1).unwrap_or(start);
+    &u[start..end.max(start)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(lines: &[&[u8]]) -> String {
+        let mut session = Session::new(Arc::new(Mutex::new(BTreeMap::new())));
+        let mut out = Vec::new();
+        for line in lines {
+            for &u in line.iter() {
+                session.receive(u, &mut out);
+            }
+        }
+        String::from_utf8_lossy(&out).to_string()
+    }
+
+    #[test]
+    fn test_sim_program() {
+        let out = run(&[b"20 PRINT \"B\";1\r", b"10 print \"A\"\r", b"30 END\r", b"LIST\r", b"RUN\r"]);
+        assert_eq!(out, "20 PRINT \"B\";1\r\n10 print \"A\"\r\n30 END\r\n\
+                         LIST\r\n10 print \"A\"\r\n20 PRINT \"B\";1\r\n30 END\r\nOk\r\n\
+                         RUN\r\nA\r\nB 1 \r\nOk\r\n");
+    }
+
+    #[test]
+    fn test_sim_break() {
+        let out = run(&[b"10 GOTO 10\r", b"RUN\r", &[U_BREAK]]);
+        assert_eq!(out, "10 GOTO 10\r\nRUN\r\nBreak in 10\r\nOk\r\n");
+        let out = run(&[b"10 ERROR 2\r", b"RUN\r", b"FOO\r"]);
+        assert_eq!(out, "10 ERROR 2\r\nRUN\r\nSyntax error in 10\r\nOk\r\nFOO\r\nSyntax error\r\nOk\r\n");
+    }
+}
@@ -0,0 +1,640 @@
+// MSX-BASIC Tokenize Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// MSX-BASIC の中間言語形式 (SAVE"FILE.BAS" で保存したファイル) の読み書き
+//
+// ファイルの構造
+//   0xFF
+//   行ごとに [次の行のアドレス 2byte] [行番号 2byte] [中間言語] 0x00
+//   最後に 0x0000
+//
+// 文字列は MSX の文字コード (msxcode) のバイト列で扱う
+//
+use std::collections::BTreeMap;
+use crate::error::{self, MsxError};
+
+// ファイルの先頭
+pub const BAS_HEADER: u8 = 0xff;
+
+// プログラムの先頭アドレス (0x8000 の次)
+const TEXT_START: u16 = 0x8001;
+
+// 1バイトの中間言語 0x81～
+pub const TOKENS: [&str; 124] = [
+    "END", "FOR", "NEXT", "DATA", "INPUT", "DIM", "READ", "LET",
+    "GOTO", "RUN", "IF", "RESTORE", "GOSUB", "RETURN", "REM", "STOP",
+    "PRINT", "CLEAR", "LIST", "NEW", "ON", "WAIT", "DEF", "POKE",
+    "CONT", "CSAVE", "CLOAD", "OUT", "LPRINT", "LLIST", "CLS", "WIDTH",
+    "ELSE", "TRON", "TROFF", "SWAP", "ERASE", "ERROR", "RESUME", "DELETE",
+    "AUTO", "RENUM", "DEFSTR", "DEFINT", "DEFSNG", "DEFDBL", "LINE", "OPEN",
+    "FIELD", "GET", "PUT", "CLOSE", "LOAD", "MERGE", "FILES", "LSET",
+    "RSET", "SAVE", "LFILES", "CIRCLE", "COLOR", "DRAW", "PAINT", "BEEP",
+    "PLAY", "PSET", "PRESET", "SOUND", "SCREEN", "VPOKE", "SPRITE", "VDP",
+    "BASE", "CALL", "TIME", "KEY", "MAX", "MOTOR", "BLOAD", "BSAVE",
+    "DSKO$", "SET", "NAME", "KILL", "IPL", "COPY", "CMD", "LOCATE",
+    "TO", "THEN", "TAB(", "STEP", "USR", "FN", "SPC(", "NOT",
+    "ERL", "ERR", "STRING$", "USING", "INSTR", "'", "VARPTR", "CSRLIN",
+    "ATTR$", "DSKI$", "OFF", "INKEY$", "POINT", ">", "=", "<",
+    "+", "-", "*", "/", "^", "AND", "OR", "XOR",
+    "EQV", "IMP", "MOD", "\\",
+];
+
+// 0xFF に続く関数の中間言語 0x81～
+pub const FUNCTIONS: [&str; 48] = [
+    "LEFT$", "RIGHT$", "MID$", "SGN", "INT", "ABS", "SQR", "RND",
+    "SIN", "LOG", "EXP", "COS", "TAN", "ATN", "FRE", "INP",
+    "POS", "LEN", "STR$", "VAL", "ASC", "CHR$", "PEEK", "VPEEK",
+    "SPACE$", "OCT$", "HEX$", "LPOS", "BIN$", "CINT", "CSNG", "CDBL",
+    "FIX", "STICK", "STRIG", "PDL", "PAD", "DSKF", "FPOS", "CVI",
+    "CVS", "CVD", "EOF", "LOC", "LOF", "MKI$", "MKS$", "MKD$",
+];
+
+const T_DATA: u8 = 0x84;
+const T_REM: u8 = 0x8f;
+const T_ELSE: u8 = 0xa1;
+const T_APOSTROPHE: u8 = 0xe6;
+const T_PRINT: u8 = 0x91;
+
+// 数値定数
+const N_OCT: u8 = 0x0b;
+const N_HEX: u8 = 0x0c;
+const N_LINE_PTR: u8 = 0x0d;
+const N_LINE: u8 = 0x0e;
+const N_BYTE: u8 = 0x0f;
+const N_DIGIT0: u8 = 0x11;
+const N_DIGIT9: u8 = 0x1a;
+const N_INT: u8 = 0x1c;
+const N_SINGLE: u8 = 0x1d;
+const N_DOUBLE: u8 = 0x1f;
+
+const GRAPH: u8 = 0x01;
+
+// 後ろに行番号が続く命令
+pub const LINE_NUMBER_TOKENS: [&str; 10] = [
+    "GOTO", "GOSUB", "THEN", "ELSE", "RESTORE", "RUN", "RESUME", "LIST", "LLIST", "DELETE",
+];
+
+fn codec_err(msg: String) -> MsxError {
+    MsxError::Codec(msg)
+}
+
+// 中間言語形式のファイルか
+pub fn is_tokenized(data: &[u8]) -> bool {
+    data.first() == Some(&BAS_HEADER)
+}
+
+//
+// 一行分の中間言語を LIST と同じ文字列 (MSX の文字コード) に戻す
+// 行末の 0x00 の次の位置も返す
+//
+fn detokenize_line(data: &[u8]) -> error::Result<(Vec<u8>, usize)> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    let need = |i: usize, n: usize| -> error::Result<&[u8]> {
+        data.get(i..i + n).ok_or_else(|| codec_err("Unexpected end of tokenized line".to_string()))
+    };
+    loop {
+        let Some(&b) = data.get(i) else {
+            return Err(codec_err("Missing end of tokenized line".to_string()));
+        };
+        i += 1;
+        match b {
+            0x00 => return Ok((out, i)),
+            GRAPH => {
+                out.push(b);
+                out.extend_from_slice(need(i, 1)?);
+                i += 1;
+            },
+            N_OCT | N_HEX | N_LINE | N_INT => {
+                let v = need(i, 2)?;
+                let v = u16::from_le_bytes([v[0], v[1]]);
+                let text = match b {
+                    N_OCT => format!("&O{:o}", v),
+                    N_HEX => format!("&H{:X}", v),
+                    _ => v.to_string(),
+                };
+                out.extend_from_slice(text.as_bytes());
+                i += 2;
+            },
+            N_LINE_PTR => return Err(codec_err("Line pointer in tokenized line".to_string())),
+            N_BYTE => {
+                out.extend_from_slice(need(i, 1)?[0].to_string().as_bytes());
+                i += 1;
+            },
+            N_DIGIT0..=N_DIGIT9 => out.push(b'0' + b - N_DIGIT0),
+            N_SINGLE => {
+                out.extend_from_slice(format_float(need(i, 4)?, false).as_bytes());
+                i += 4;
+            },
+            N_DOUBLE => {
+                out.extend_from_slice(format_float(need(i, 8)?, true).as_bytes());
+                i += 8;
+            },
+            b'"' => {
+                // 文字列は閉じる " までそのまま
+                out.push(b);
+                while let Some(&c) = data.get(i) {
+                    if c == 0x00 {
+                        break;
+                    }
+                    out.push(c);
+                    i += 1;
+                    if c == b'"' {
+                        break;
+                    }
+                }
+            },
+            b':' if data.get(i) == Some(&T_ELSE) => {
+                // ELSE の前には : が入っている
+                out.extend_from_slice(b"ELSE");
+                i += 1;
+            },
+            b':' if data.get(i..i + 2) == Some(&[T_REM, T_APOSTROPHE]) => {
+                // ' は :REM' で記録されている
+                out.push(b'\'');
+                i += 2;
+                i += copy_rest(&data[i..], &mut out, false);
+            },
+            T_REM => {
+                out.extend_from_slice(b"REM");
+                i += copy_rest(&data[i..], &mut out, false);
+            },
+            T_DATA => {
+                out.extend_from_slice(b"DATA");
+                i += copy_rest(&data[i..], &mut out, true);
+            },
+            0x81..=0xfc => out.extend_from_slice(TOKENS[(b - 0x81) as usize].as_bytes()),
+            0xff => {
+                let f = need(i, 1)?[0];
+                match f.checked_sub(0x81).and_then(|n| FUNCTIONS.get(n as usize)) {
+                    Some(name) => out.extend_from_slice(name.as_bytes()),
+                    None => return Err(codec_err(format!("Unknown function token FF {:02X}", f))),
+                }
+                i += 1;
+            },
+            _ => out.push(b),
+        }
+    }
+}
+
+// REM や DATA の後ろをそのまま写す
+// DATA は文字列の外の : までになる
+fn copy_rest(data: &[u8], out: &mut Vec<u8>, until_colon: bool) -> usize {
+    let mut quoted = false;
+    for (n, &c) in data.iter().enumerate() {
+        if c == 0x00 || (until_colon && c == b':' && !quoted) {
+            return n;
+        }
+        if c == b'"' {
+            quoted = !quoted;
+        }
+        out.push(c);
+    }
+    data.len()
+}
+
+//
+// BCD の浮動小数点数を文字列にする
+// 先頭バイトは 符号 + 指数 (0x40 が 10^0)、残りが仮数部 0.dddd...
+//
+fn format_float(bytes: &[u8], double: bool) -> String {
+    let exp = (bytes[0] & 0x7f) as i32 - 0x40;
+    let mut digits: Vec<u8> = bytes[1..].iter().flat_map(|b| [b >> 4, b & 0x0f]).collect();
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    let sign = if bytes[0] & 0x80 != 0 { "-" } else { "" };
+    if digits.is_empty() {
+        return if double { "0#".to_string() } else { "0!".to_string() };
+    }
+    let text: String = digits.iter().map(|d| (b'0' + d) as char).collect();
+    let len = digits.len() as i32;
+    let max = if double { 14 } else { 6 };
+    let suffix = if double { '#' } else { '!' };
+
+    if exp > max || exp < -1 {
+        // 指数表記
+        let e = exp - 1;
+        let mark = if double { 'D' } else { 'E' };
+        let frac = if text.len() > 1 { format!(".{}", &text[1..]) } else { String::new() };
+        return format!("{}{}{}{}{}{:02}", sign, &text[..1], frac, mark, if e < 0 { '-' } else { '+' }, e.abs());
+    }
+    let mut out = sign.to_string();
+    if exp >= len {
+        out.push_str(&text);
+        out.push_str(&"0".repeat((exp - len) as usize));
+        // 小数点がないと整数になってしまう
+        out.push(suffix);
+        return out;
+    }
+    if exp > 0 {
+        out.push_str(&text[..exp as usize]);
+        out.push('.');
+        out.push_str(&text[exp as usize..]);
+    } else {
+        out.push('.');
+        out.push_str(&"0".repeat((-exp) as usize));
+        out.push_str(&text);
+    }
+    // 倍精度でも桁が少ないと単精度として読まれる
+    if double && len <= 6 {
+        out.push(suffix);
+    }
+    out
+}
+
+//
+// 数値の文字列を BCD の浮動小数点数にする
+// digits は仮数部の数字、exp は 0.dddd × 10^exp の指数
+//
+fn encode_float(mut digits: Vec<u8>, mut exp: i32, double: bool) -> error::Result<Vec<u8>> {
+    let size = if double { 14 } else { 6 };
+    // 先頭の 0 を除く
+    while digits.first() == Some(&0) {
+        digits.remove(0);
+        exp -= 1;
+    }
+    if digits.is_empty() {
+        return Ok(vec![0; size / 2 + 1]);
+    }
+    // 四捨五入
+    if digits.len() > size {
+        let round_up = digits[size] >= 5;
+        digits.truncate(size);
+        if round_up {
+            let mut n = size;
+            loop {
+                if n == 0 {
+                    digits.insert(0, 1);
+                    digits.truncate(size);
+                    exp += 1;
+                    break;
+                }
+                n -= 1;
+                if digits[n] == 9 {
+                    digits[n] = 0;
+                } else {
+                    digits[n] += 1;
+                    break;
+                }
+            }
+        }
+    }
+    digits.resize(size, 0);
+    if !(-63..=63).contains(&exp) {
+        return Err(codec_err("Overflow".to_string()));
+    }
+    let mut out = vec![(0x40 + exp) as u8];
+    out.extend(digits.chunks(2).map(|d| (d[0] << 4) | d[1]));
+    Ok(out)
+}
+
+// 数値定数を読み取って中間言語にする
+// 読み取ったバイト数を返す
+fn tokenize_number(src: &[u8], out: &mut Vec<u8>) -> error::Result<usize> {
+    let mut i = 0;
+    let mut int_part = Vec::new();
+    let mut frac_part = Vec::new();
+    let mut has_point = false;
+    while let Some(&c) = src.get(i) {
+        if c.is_ascii_digit() {
+            if has_point {
+                frac_part.push(c - b'0');
+            } else {
+                int_part.push(c - b'0');
+            }
+        } else if c == b'.' && !has_point {
+            has_point = true;
+        } else if c != b' ' || has_point {
+            break;
+        } else if !src.get(i + 1).is_some_and(|c| c.is_ascii_digit()) {
+            // 数字の間の空白は詰める (1 000 = 1000)
+            break;
+        }
+        i += 1;
+    }
+    // 指数部
+    let mut exponent: Option<(u8, i32)> = None;
+    if let Some(&m) = src.get(i) {
+        let m = m.to_ascii_uppercase();
+        if m == b'E' || m == b'D' {
+            let mut j = i + 1;
+            let mut neg = false;
+            if let Some(&s) = src.get(j) {
+                if s == b'+' || s == b'-' {
+                    neg = s == b'-';
+                    j += 1;
+                }
+            }
+            let start = j;
+            let mut e = 0_i32;
+            while let Some(&c) = src.get(j) {
+                if !c.is_ascii_digit() {
+                    break;
+                }
+                e = (e * 10 + (c - b'0') as i32).min(999);
+                j += 1;
+            }
+            if j > start {
+                exponent = Some((m, if neg { -e } else { e }));
+                i = j;
+            }
+        }
+    }
+    let suffix = match src.get(i) {
+        Some(&c) if c == b'%' || c == b'!' || c == b'#' => {
+            i += 1;
+            Some(c)
+        },
+        _ => None,
+    };
+
+    if !has_point && exponent.is_none() && (suffix.is_none() || suffix == Some(b'%')) {
+        let value = int_part.iter().fold(0_u32, |v, &d| v.saturating_mul(10).saturating_add(d as u32));
+        if value <= 32767 {
+            match value {
+                0..=9 => out.push(N_DIGIT0 + value as u8),
+                10..=255 => out.extend_from_slice(&[N_BYTE, value as u8]),
+                _ => {
+                    out.push(N_INT);
+                    out.extend_from_slice(&(value as u16).to_le_bytes());
+                },
+            }
+            return Ok(i);
+        }
+        if suffix == Some(b'%') {
+            return Err(codec_err("Overflow".to_string()));
+        }
+    }
+
+    let significant = int_part.iter().chain(frac_part.iter()).skip_while(|&&d| d == 0).count();
+    let double = suffix == Some(b'#')
+        || matches!(exponent, Some((b'D', _)))
+        || (suffix.is_none() && !matches!(exponent, Some((b'E', _))) && significant > 6);
+    let exp = int_part.len() as i32 + exponent.map_or(0, |(_, e)| e);
+    let mut digits = int_part;
+    digits.extend(frac_part);
+    out.push(if double { N_DOUBLE } else { N_SINGLE });
+    out.extend(encode_float(digits, exp, double)?);
+    Ok(i)
+}
+
+// src の先頭から一致する命令を探す (一番長いもの)
+pub fn match_keyword(src: &[u8]) -> Option<(Vec<u8>, &'static str)> {
+    let upper: Vec<u8> = src.iter().take(8).map(|c| c.to_ascii_uppercase()).collect();
+    let mut best: Option<(Vec<u8>, &'static str)> = None;
+    let candidates = TOKENS.iter().enumerate()
+        .map(|(n, k)| (vec![0x81 + n as u8], *k))
+        .chain(FUNCTIONS.iter().enumerate().map(|(n, k)| (vec![0xff, 0x81 + n as u8], *k)));
+    for (code, keyword) in candidates {
+        // 記号は別に扱う
+        if !keyword.as_bytes()[0].is_ascii_alphabetic() {
+            continue;
+        }
+        if upper.starts_with(keyword.as_bytes()) && best.as_ref().is_none_or(|(_, k)| keyword.len() > k.len()) {
+            best = Some((code, keyword));
+        }
+    }
+    best
+}
+
+fn operator_token(c: u8) -> Option<u8> {
+    TOKENS.iter().position(|k| k.len() == 1 && k.as_bytes()[0] == c && c != b'\'').map(|n| 0x81 + n as u8)
+}
+
+//
+// 一行分 (行番号の後ろ) を中間言語にする
+//
+pub fn tokenize_line(src: &[u8]) -> error::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    // 行番号を取る命令の後ろ
+    let mut line_number = false;
+    // 変数名の途中 (数字も変数名の一部になる)
+    let mut in_name = false;
+    while i < src.len() {
+        let c = src[i];
+        if c == b'"' {
+            let end = src[i + 1..].iter().position(|&c| c == b'"').map_or(src.len(), |n| i + n + 2);
+            out.extend_from_slice(&src[i..end]);
+            i = end;
+            in_name = false;
+            line_number = false;
+            continue;
+        }
+        if c == b'\'' {
+            out.extend_from_slice(&[b':', T_REM, T_APOSTROPHE]);
+            out.extend_from_slice(&src[i + 1..]);
+            break;
+        }
+        if c == GRAPH {
+            out.extend_from_slice(&src[i..(i + 2).min(src.len())]);
+            i += 2;
+            in_name = false;
+            continue;
+        }
+        if c.is_ascii_alphabetic() {
+            if let Some((code, keyword)) = match_keyword(&src[i..]) {
+                i += keyword.len();
+                in_name = false;
+                line_number = LINE_NUMBER_TOKENS.contains(&keyword);
+                match code[0] {
+                    T_REM => {
+                        out.push(T_REM);
+                        out.extend_from_slice(&src[i..]);
+                        break;
+                    },
+                    T_DATA => {
+                        out.push(T_DATA);
+                        let mut rest = Vec::new();
+                        i += copy_rest(&src[i..], &mut rest, true);
+                        out.extend(rest);
+                    },
+                    T_ELSE => out.extend_from_slice(&[b':', T_ELSE]),
+                    _ => out.extend(code),
+                }
+                continue;
+            }
+            out.push(c.to_ascii_uppercase());
+            in_name = true;
+            line_number = false;
+            i += 1;
+            continue;
+        }
+        if c.is_ascii_digit() && in_name {
+            out.push(c);
+            i += 1;
+            continue;
+        }
+        in_name = false;
+        if c.is_ascii_digit() && line_number {
+            let len = src[i..].iter().take_while(|c| c.is_ascii_digit()).count();
+            let text = std::str::from_utf8(&src[i..i + len]).unwrap_or("");
+            let num = text.parse::<u16>().map_err(|_| codec_err(format!("Bad line number: {}", text)))?;
+            out.push(N_LINE);
+            out.extend_from_slice(&num.to_le_bytes());
+            i += len;
+            continue;
+        }
+        if c.is_ascii_digit() || (c == b'.' && src.get(i + 1).is_some_and(|c| c.is_ascii_digit())) {
+            i += tokenize_number(&src[i..], &mut out)?;
+            continue;
+        }
+        if c == b'&' {
+            let kind = src.get(i + 1).map(|c| c.to_ascii_uppercase());
+            let (radix, code) = match kind {
+                Some(b'H') => (16, N_HEX),
+                Some(b'O') => (8, N_OCT),
+                _ => (0, 0),
+            };
+            if radix != 0 {
+                let len = src[i + 2..].iter().take_while(|c| (**c as char).is_digit(radix)).count();
+                let text = std::str::from_utf8(&src[i + 2..i + 2 + len]).unwrap_or("");
+                let v = u16::from_str_radix(if text.is_empty() { "0" } else { text }, radix)
+                    .map_err(|_| codec_err("Overflow".to_string()))?;
+                out.push(code);
+                out.extend_from_slice(&v.to_le_bytes());
+                i += 2 + len;
+                continue;
+            }
+        }
+        if c == b'?' {
+            out.push(T_PRINT);
+            i += 1;
+            line_number = false;
+            continue;
+        }
+        if let Some(t) = operator_token(c) {
+            out.push(t);
+            if c != b'-' {
+                line_number = false;
+            }
+            i += 1;
+            continue;
+        }
+        if c != b' ' && c != b',' {
+            line_number = false;
+        }
+        out.push(c);
+        i += 1;
+    }
+    Ok(out)
+}
+
+//
+// 中間言語にしてから戻し、MSX の LIST で表示される形にする
+// (? は PRINT、GOTO 0100 は GOTO 100、命令や変数名は大文字になる)
+//
+pub fn list_form(line: &[u8]) -> error::Result<Vec<u8>> {
+    let mut data = tokenize_line(line)?;
+    data.push(0);
+    Ok(detokenize_line(&data)?.0)
+}
+
+//
+// 中間言語形式のファイルを読み込んで 行番号 と 文字列 (MSX の文字コード) の組にする
+//
+pub fn decode_program(data: &[u8]) -> error::Result<Vec<(u16, Vec<u8>)>> {
+    if !is_tokenized(data) {
+        return Err(codec_err("Not a tokenized BASIC file".to_string()));
+    }
+    let mut lines = Vec::new();
+    let mut i = 1;
+    loop {
+        let Some(link) = data.get(i..i + 2) else {
+            // 終わりの 0x0000 がないファイルもある
+            return Ok(lines);
+        };
+        if link == [0, 0] {
+            return Ok(lines);
+        }
+        let num = data.get(i + 2..i + 4)
+            .ok_or_else(|| codec_err("Unexpected end of file".to_string()))?;
+        let num = u16::from_le_bytes([num[0], num[1]]);
+        let (text, len) = detokenize_line(&data[i + 4..])?;
+        lines.push((num, text));
+        i += 4 + len;
+    }
+}
+
+//
+// プログラムを中間言語形式のファイルにする
+// program は 行番号 と 行の内容 (MSX の文字コード)
+//
+pub fn encode_program(program: &BTreeMap<u16, Vec<u8>>) -> error::Result<Vec<u8>> {
+    let mut out = vec![BAS_HEADER];
+    let mut addr = TEXT_START;
+    for (num, text) in program {
+        let start = text.iter().position(|&c| c != b' ').unwrap_or(text.len());
+        let body = tokenize_line(&text[start..])
+            .map_err(|e| codec_err(format!("{} in {}", e, num)))?;
+        let next = body.len() + 5;
+        addr = u16::try_from(addr as usize + next).map_err(|_| codec_err("Out of memory".to_string()))?;
+        out.extend_from_slice(&addr.to_le_bytes());
+        out.extend_from_slice(&num.to_le_bytes());
+        out.extend(body);
+        out.push(0x00);
+    }
+    out.extend_from_slice(&[0x00, 0x00]);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(line: &str) -> String {
+        let body = tokenize_line(line.as_bytes()).unwrap();
+        let mut data = body.clone();
+        data.push(0);
+        let (text, len) = detokenize_line(&data).unwrap();
+        assert_eq!(len, data.len());
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn test_tokenize_line() {
+        assert_eq!(tokenize_line(b"PRINT \"HELLO\"").unwrap(), b"\x91 \"HELLO\"");
+        assert_eq!(tokenize_line(b"IF A=1 THEN 10 ELSE 300").unwrap(),
+            [0x8b, b' ', b'A', 0xef, 0x12, b' ', 0xda, b' ', 0x0e, 10, 0, b' ', b':', 0xa1, b' ', 0x0e, 0x2c, 0x01]);
+        assert_eq!(tokenize_line(b"a=1000:b=&HFF' end").unwrap(),
+            [b'A', 0xef, 0x1c, 0xe8, 0x03, b':', b'B', 0xef, 0x0c, 0xff, 0x00, b':', 0x8f, 0xe6, b' ', b'e', b'n', b'd']);
+        assert_eq!(tokenize_line(b"X=1.5:Y=LEFT$(A1$,2)").unwrap(),
+            [b'X', 0xef, 0x1d, 0x41, 0x15, 0x00, 0x00, b':', b'Y', 0xef, 0xff, 0x81, b'(', b'A', b'1', b'$', b',', 0x13, b')']);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for line in [
+            "PRINT \"HELLO\"",
+            "FOR I=1 TO 10 STEP 2:NEXT I",
+            "IF A=1 THEN 10 ELSE 300",
+            "ON X GOTO 100,200,300",
+            "A=100000!:B=1.5#:C=3.14159265:D=.5:E=1E-05",
+            "DATA 1,\"A:B\",xyz:READ A$",
+            "REM lower case",
+            "CLS ' comment",
+            "A=&H1F+&O17-32767",
+        ] {
+            assert_eq!(roundtrip(line), line);
+        }
+        assert_eq!(roundtrip("?a"), "PRINTA");
+        assert_eq!(roundtrip("a=10000000"), "A=10000000#");
+    }
+
+    #[test]
+    fn test_program() {
+        let program: BTreeMap<u16, Vec<u8>> = [
+            (10, b"PRINT \"HELLO\"".to_vec()),
+            (20, b"GOTO 10".to_vec()),
+        ].into_iter().collect();
+        let data = encode_program(&program).unwrap();
+        assert_eq!(data[..14], [0xff, 0x0f, 0x80, 0x0a, 0x00, 0x91, b' ', b'"', b'H', b'E', b'L', b'L', b'O', b'"']);
+        assert_eq!(data[14..], [0x00, 0x19, 0x80, 0x14, 0x00, 0x89, b' ', 0x0e, 0x0a, 0x00, 0x00, 0x00, 0x00]);
+        let lines = decode_program(&data).unwrap();
+        assert_eq!(lines, program.into_iter().collect::<Vec<_>>());
+        assert!(decode_program(b"10 PRINT").is_err());
+    }
+}
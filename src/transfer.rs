@@ -0,0 +1,388 @@
+// MSX Program Transfer Module
+// Copyright (c) 2023 Akio Setsumasa
+// Released under the MIT license
+// https://github.com/akio-se/msxterm
+//
+// MSX の応答を待ちながらプログラムをやりとりする
+//
+use std::io::Write;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+use crate::error::{self, MsxError};
+use crate::{Command, Link, Msxterm, C_CR};
+
+// 応答が途切れてから諦めるまでの時間
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// 一行送ってからエコーが返るまでの待ち時間
+const LINE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// MSX のプロンプト
+const PROMPT: &str = "Ok";
+
+//
+// 受信した行を待つ
+// 受信スレッドが止まった場合もタイムアウトとして扱う
+//
+pub fn recv_line(rx: &Receiver<String>, timeout: Duration, waiting: &str) -> error::Result<String> {
+    match rx.recv_timeout(timeout) {
+        Ok(line) => Ok(line),
+        Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+            Err(MsxError::Timeout(waiting.to_string()))
+        },
+    }
+}
+
+// 行番号で始まる行か
+fn is_program_line(line: &str) -> bool {
+    line.starts_with(|c: char| c.is_ascii_digit())
+}
+
+//
+// LIST を送って MSX 側のプログラムを取り込む
+// LIST のエコーから Ok が返るまでの行番号付きの行を返す
+// 取り込む間は受信した内容を表示しない
+//
+pub fn capture_list(msxterm: &Msxterm, link: &Link) -> error::Result<Vec<String>> {
+    // watch の受付を待つ間に Quiet も処理される
+    link.command(Command::Quiet(true))?;
+    let result = link.watch().and_then(|rx| list_lines(msxterm, link, &rx));
+    link.unwatch();
+    let _ = link.command(Command::Quiet(false));
+    result
+}
+
+fn list_lines(msxterm: &Msxterm, link: &Link, rx: &Receiver<String>) -> error::Result<Vec<String>> {
+    link.send(&msxterm.encode(&format!("LIST{}", C_CR))?)?;
+    // それ以前に受信していた行は読み捨てる
+    while !recv_line(rx, CAPTURE_TIMEOUT, "LIST")?.trim().eq_ignore_ascii_case("LIST") {}
+    let mut listing = Vec::new();
+    loop {
+        let line = recv_line(rx, CAPTURE_TIMEOUT, PROMPT)?;
+        if line.trim_end() == PROMPT {
+            return Ok(listing);
+        }
+        if is_program_line(&line) {
+            listing.push(line.trim_end().to_string());
+        }
+    }
+}
+
+// MSX のエラーメッセージか
+fn is_error_message(line: &str) -> bool {
+    let lower = line.trim().to_lowercase();
+    lower.ends_with(" error")
+        || lower.contains(" error in ")
+        || lower.starts_with("out of ")
+        || lower.starts_with("undefined line number")
+        || lower.starts_with("line buffer overflow")
+}
+
+// 送った行のエコーか
+// 小文字送信モードや行末の空白の違いは無視する
+pub fn is_echo(received: &str, sent: &str) -> bool {
+    received.trim().to_lowercase() == sent.trim().to_lowercase()
+}
+
+fn is_syntax_error(message: &str) -> bool {
+    message.trim().eq_ignore_ascii_case("Syntax error")
+}
+
+// エラーが遅れて届くのを待つ時間
+const SETTLE_TIME: Duration = Duration::from_millis(300);
+
+// エコーが崩れた行を送り直す回数
+const ECHO_RETRY: usize = 2;
+
+//
+// 一行ずつエコーを確かめながら送る
+// MSX のエラーはエコーの後に届くので、直前に送った行のエラーとして扱う
+// 送っている間は受信した内容を表示しない
+//
+struct Pacer<'a> {
+    msxterm: &'a Msxterm,
+    link: &'a Link,
+    rx: Receiver<String>,
+    last: String,
+    retried: usize,
+    // MSX 側に送った行数 (送り直しは数えない)
+    sent: usize,
+}
+
+impl<'a> Pacer<'a> {
+    fn new(msxterm: &'a Msxterm, link: &'a Link) -> error::Result<Pacer<'a>> {
+        // watch の受付を待つ間に Quiet も処理される
+        link.command(Command::Quiet(true))?;
+        let rx = link.watch()?;
+        Ok(Pacer { msxterm, link, rx, last: String::new(), retried: 0, sent: 0 })
+    }
+
+    fn check(&self, received: &str) -> error::Result<()> {
+        if is_error_message(received) {
+            return Err(MsxError::Device(self.last.clone(), received.trim().to_string()));
+        }
+        Ok(())
+    }
+
+    // 一行送ってエコーを待つ
+    // エコーが崩れて Syntax error、プロンプト、無応答のいずれかになった場合は送り直す
+    fn send_line(&mut self, line: &str) -> error::Result<()> {
+        let mut retry = 0;
+        loop {
+            self.link.send(&self.msxterm.encode(&format!("{}{}", line, C_CR))?)?;
+            if retry == 0 {
+                self.sent += 1;
+            }
+            match self.wait_echo(line) {
+                Ok(true) => return Ok(()),
+                Ok(false) if retry < ECHO_RETRY => {},
+                Ok(false) => return Err(MsxError::Device(line.to_string(), "Garbled echo".to_string())),
+                Err(MsxError::Device(bad, e)) if bad == line && is_syntax_error(&e) && retry < ECHO_RETRY => {},
+                Err(e) => return Err(e),
+            }
+            retry += 1;
+            self.retried += 1;
+        }
+    }
+
+    // エコーが返れば true、崩れたエコーの後にプロンプトが来るか応答が途切れたら false
+    fn wait_echo(&mut self, line: &str) -> error::Result<bool> {
+        // 送った行と違うエコーが届いたか
+        let mut garbled = false;
+        loop {
+            let received = if garbled {
+                match self.rx.recv_timeout(SETTLE_TIME) {
+                    Ok(received) => received,
+                    Err(_) => return Ok(false),
+                }
+            } else {
+                recv_line(&self.rx, LINE_TIMEOUT, line)?
+            };
+            if is_echo(&received, line) {
+                self.last = line.to_string();
+                return Ok(true);
+            }
+            if garbled && is_error_message(&received) {
+                return Err(MsxError::Device(line.to_string(), received.trim().to_string()));
+            }
+            if garbled && received.trim_end() == PROMPT {
+                return Ok(false);
+            }
+            self.check(&received)?;
+            if received.trim_end() != PROMPT {
+                garbled = true;
+            }
+        }
+    }
+
+    // Ok が返るまで待つ
+    fn wait_prompt(&self) -> error::Result<()> {
+        loop {
+            let received = recv_line(&self.rx, CAPTURE_TIMEOUT, PROMPT)?;
+            if received.trim_end() == PROMPT {
+                return Ok(());
+            }
+            self.check(&received)?;
+        }
+    }
+
+    // 最後の行のエラーを待つ
+    fn settle(&self) -> error::Result<()> {
+        while let Ok(received) = self.rx.recv_timeout(SETTLE_TIME) {
+            self.check(&received)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Pacer<'_> {
+    fn drop(&mut self) {
+        self.link.unwatch();
+        let _ = self.link.command(Command::Quiet(false));
+    }
+}
+
+//
+// MSX 側のプログラムを NEW してから lines を一行ずつ送る
+// 送った行数を返す
+//
+pub fn reload_to(msxterm: &Msxterm, link: &Link, lines: &[String]) -> error::Result<usize> {
+    let mut pacer = Pacer::new(msxterm, link)?;
+    pacer.send_line("NEW")?;
+    pacer.wait_prompt()?;
+    pacer.sent = 0;
+    send_all(&mut pacer, lines)
+}
+
+//
+// lines を一行ずつ送る (#load, #sync 用)
+// 送った行数を返す
+//
+pub fn send_lines(msxterm: &Msxterm, link: &Link, lines: &[String]) -> error::Result<usize> {
+    let mut pacer = Pacer::new(msxterm, link)?;
+    send_all(&mut pacer, lines)
+}
+
+// 中断した場合は MSX 側に届いた行を知らせる
+// 前の行のエラーが届くのは次の行を送った後なので、その行も送られている
+fn send_all(pacer: &mut Pacer, lines: &[String]) -> error::Result<usize> {
+    let result = send_with_progress(pacer, lines);
+    eprintln!();
+    if pacer.retried > 0 {
+        eprintln!("{} lines resent after a garbled echo.", pacer.retried);
+    }
+    if result.is_err() && pacer.sent > 0 {
+        eprintln!("{} of {} lines sent. (last: {})", pacer.sent, lines.len(), lines[pacer.sent - 1]);
+    }
+    result
+}
+
+// 進み具合は標準エラー出力に書く (msxterm run の標準出力を汚さない)
+fn send_with_progress(pacer: &mut Pacer, lines: &[String]) -> error::Result<usize> {
+    let mut out = std::io::stderr();
+    for (i, line) in lines.iter().enumerate() {
+        pacer.send_line(line)?;
+        let _ = write!(out, "\rSending {}/{}", i + 1, lines.len());
+        let _ = out.flush();
+    }
+    pacer.settle()?;
+    Ok(lines.len())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use rustyline::ExternalPrinter;
+    use crate::connection::{self, SerialParam};
+    use crate::{receive_loop, sim};
+
+    // 表示を捨てるプリンタ
+    struct NullPrinter;
+
+    impl ExternalPrinter for NullPrinter {
+        fn print(&mut self, _msg: String) -> rustyline::Result<()> {
+            Ok(())
+        }
+    }
+
+    // シミュレータに接続して受信スレッドを動かす
+    pub fn sim_link() -> Link {
+        let addr = sim::Simulator::bind("127.0.0.1:0").unwrap().spawn().unwrap().to_string();
+        let conn = connection::create_connection(&addr, &SerialParam::default()).unwrap();
+        let conn_read = conn.try_clone().unwrap();
+        let (tx, rx) = channel();
+        let link = Link { conn: Arc::new(Mutex::new(conn)), tx, log: Arc::new(Mutex::new(None)) };
+        let conn_shared = Arc::clone(&link.conn);
+        let log_shared = Arc::clone(&link.log);
+        thread::spawn(move || {
+            receive_loop(conn_read, conn_shared, log_shared, addr, SerialParam::default(), rx, NullPrinter);
+        });
+        link
+    }
+
+    #[test]
+    fn test_capture_list() {
+        let link = sim_link();
+        let mt = Msxterm::new();
+        link.send(&mt.encode("20 GOTO 10\r10 PRINT \"HELLO\"\r").unwrap()).unwrap();
+        let listing = capture_list(&mt, &link).unwrap();
+        assert_eq!(listing, ["10 PRINT \"HELLO\"", "20 GOTO 10"]);
+
+        link.send(b"NEW\r").unwrap();
+        assert!(capture_list(&mt, &link).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reload_to() {
+        let link = sim_link();
+        let mt = Msxterm::new();
+        link.send(b"10 PRINT \"OLD\"\r30 END\r").unwrap();
+        let lines = vec!["10 PRINT \"NEW\"".to_string(), "20 GOTO 10".to_string()];
+        assert_eq!(reload_to(&mt, &link, &lines).unwrap(), 2);
+        assert_eq!(capture_list(&mt, &link).unwrap(), lines);
+
+        // MSX 側で受け付けられない行
+        let bad = vec!["70000 END".to_string(), "10 PRINT 1".to_string()];
+        match reload_to(&mt, &link, &bad) {
+            Err(MsxError::Device(line, e)) => assert_eq!((line.as_str(), e.as_str()), ("70000 END", "Syntax error")),
+            r => panic!("{:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_syntax_retry() {
+        // 取りこぼしたエコーとエラーを偽装する
+        let link = sim_link();
+        let mt = Msxterm::new();
+        let (tx, rx) = channel();
+        let mut pacer = Pacer { msxterm: &mt, link: &link, rx, last: String::new(), retried: 0, sent: 0 };
+        for l in ["10 PINT 1", "Syntax error", "Ok", "10 PRINT 1"] {
+            tx.send(l.to_string()).unwrap();
+        }
+        pacer.send_line("10 PRINT 1").unwrap();
+        assert_eq!(pacer.retried, 1);
+
+        // エコーが正しい行のエラーは送り直さない
+        for l in ["70000 END", "Syntax error"] {
+            tx.send(l.to_string()).unwrap();
+        }
+        pacer.send_line("70000 END").unwrap();
+        assert!(matches!(pacer.settle(), Err(MsxError::Device(..))));
+        assert_eq!(pacer.retried, 1);
+
+        // 崩れたエコーの後にプロンプトが来るか応答が途切れても送り直す
+        for l in ["20 GOTO1O", "Ok", "20 GOTO 10"] {
+            tx.send(l.to_string()).unwrap();
+        }
+        pacer.send_line("20 GOTO 10").unwrap();
+        assert_eq!(pacer.retried, 2);
+        tx.send("30 ED".to_string()).unwrap();
+        let sender = tx.clone();
+        thread::spawn(move || {
+            thread::sleep(SETTLE_TIME * 2);
+            let _ = sender.send("30 END".to_string());
+        });
+        pacer.send_line("30 END").unwrap();
+        assert_eq!(pacer.retried, 3);
+        // 送り直しても崩れる行は中断する
+        for l in ["40 ?", "Ok"].repeat(3) {
+            tx.send(l.to_string()).unwrap();
+        }
+        match pacer.send_line("40 STOP") {
+            Err(MsxError::Device(line, e)) => assert_eq!((line.as_str(), e.as_str()), ("40 STOP", "Garbled echo")),
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(pacer.sent, 5);
+    }
+
+    #[test]
+    fn test_late_error() {
+        // 前の行のエラーが次の行を送った後に届いた場合も、送った行数を数えておく
+        let link = sim_link();
+        let mt = Msxterm::new();
+        let (tx, rx) = channel();
+        let mut pacer = Pacer { msxterm: &mt, link: &link, rx, last: String::new(), retried: 0, sent: 0 };
+        for l in ["10 A=1", "Out of memory"] {
+            tx.send(l.to_string()).unwrap();
+        }
+        let lines = vec!["10 A=1".to_string(), "20 B=2".to_string(), "30 END".to_string()];
+        match send_all(&mut pacer, &lines) {
+            Err(MsxError::Device(line, e)) => assert_eq!((line.as_str(), e.as_str()), ("10 A=1", "Out of memory")),
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(pacer.sent, 2);
+    }
+
+    #[test]
+    fn test_send_lines() {
+        let link = sim_link();
+        let mt = Msxterm::new();
+        link.send(b"10 PRINT 1\r20 END\r").unwrap();
+        let lines = vec!["10 PRINT 2".to_string(), "20".to_string(), "30 END".to_string()];
+        assert_eq!(send_lines(&mt, &link, &lines).unwrap(), 3);
+        assert_eq!(capture_list(&mt, &link).unwrap(), ["10 PRINT 2", "30 END"]);
+    }
+}